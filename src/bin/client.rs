@@ -1,5 +1,5 @@
 use std::net::UdpSocket;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use bevy::window::{CursorGrabMode, CursorOptions};
 use bevy_royal::*;
 // use bevy_inspector_egui::{bevy_egui::EguiPlugin, quick::WorldInspectorPlugin};
@@ -14,11 +14,80 @@ const FOG_COLOR: Color = Color::srgb(0.15, 0.20, 0.30);
 pub struct IncomingReceiver(crossbeam::channel::Receiver<ServerMessage>);
 
 #[derive(Resource)]
-pub struct NewestPositionUpdateUnixTime(u64);
+pub struct NetStatsReceiver(crossbeam::channel::Receiver<NetStats>);
+
+/// inputs that were sent but not acked by the server yet, and what happened between two ticks
+#[derive(Resource, Default)]
+pub struct InputState {
+    next_sequence: u32,
+    unacked: VecDeque<InputCommand>,
+    jump: bool,
+    // direction of the shot and the look at the moment of the click
+    fire: Option<(Vec3, Quat)>,
+}
+
+impl InputState {
+    const MAX_UNACKED: usize = 128;
+}
+
+/// the latest snapshots from the server, the ones it compresses against have to be kept around
+#[derive(Resource, Default)]
+pub struct ReceivedSnapshots {
+    // only complete ones, the server compresses against the snapshots that were acked
+    history: SnapshotHistory,
+    // the newest sequence, the parts of it that arrived and what they contained
+    newest: Option<u32>,
+    parts: HashSet<u8>,
+    partial: Snapshot,
+}
 
 #[derive(Resource)]
 pub struct OutgoingSender(crossbeam::channel::Sender<ClientMessage>);
 
+/// estimate of the servers clock, measured with ping / pong like ntp does
+#[derive(Resource, Default)]
+pub struct ServerClock {
+    pub rtt_ms: u64,
+    // server unix time minus client unix time, only shown in the net graph
+    pub offset_ms: i64,
+    // (rtt, offset) of the latest pongs
+    samples: VecDeque<(u64, i64)>,
+}
+
+impl ServerClock {
+    const SAMPLES: usize = 8;
+    const PING_INTERVAL: f32 = 0.5;
+
+    fn add_sample(&mut self, client_send: u64, server_time: u64, client_receive: u64) {
+        let rtt = client_receive.saturating_sub(client_send);
+        // assumes the server answered halfway through the round trip
+        let offset = server_time as i64 - ((client_send + client_receive) / 2) as i64;
+
+        self.samples.push_back((rtt, offset));
+        if self.samples.len() > Self::SAMPLES {
+            self.samples.pop_front();
+        }
+
+        self.rtt_ms = self.samples.iter().map(|(rtt, _)| *rtt).sum::<u64>() / self.samples.len() as u64;
+        // the sample with the lowest rtt had the least room for asymmetric delays
+        if let Some((_, offset)) = self.samples.iter().min_by_key(|(rtt, _)| *rtt) {
+            self.offset_ms = *offset;
+        }
+    }
+}
+
+/// login and challenge response are unreliable, the latest one is sent again until the server answers it
+#[derive(Resource)]
+struct PendingLogin(Option<ClientMessage>);
+
+impl PendingLogin {
+    const RESEND_INTERVAL: f32 = 1.;
+}
+
+/// the token of the challenge, the server only takes a disconnect that carries it
+#[derive(Resource, Default)]
+struct ChallengeToken(Option<u64>);
+
 #[derive(Resource, Default)]
 struct NetIDMap(HashMap<Entity, NetIDType>);
 
@@ -31,13 +100,74 @@ struct PlayerMaterials {
     destroyed: Handle<StandardMaterial>,
 }
 
+/// what the controlled player did in every tick the server didnt confirm yet, so it can be replayed after a correction
+#[derive(Component, Default)]
+struct PredictionHistory(VecDeque<PredictedTick>);
+
+#[derive(Debug, Clone, Copy)]
+struct PredictedTick {
+    input: InputCommand,
+    // the state after the input was applied
+    state: MovementState,
+}
+
+/// one entry per tick, enough for a second of round trip at the highest tick rate
+const PREDICTION_HISTORY: usize = 128;
+/// predictions that are closer than this to the server are left alone, above the quantization error
+const RECONCILE_EPSILON: f32 = 0.05;
+
+/// the newest state of the controlled player from the server, reconciled in the next tick
+#[derive(Resource, Default)]
+struct AuthoritativeState(Option<ServerPlayerState>);
+
+#[derive(Debug, Clone, Copy)]
+struct ServerPlayerState {
+    input_ack: u32,
+    state: MovementState,
+}
+
+/// how corrections of the controlled player are shown, it moves to the corrected position right away
+/// but its mesh and camera stay where they were and catch up over the next frames
+#[derive(Resource, Debug, Clone, Copy)]
+struct CorrectionSmoothing {
+    // how fast the visual catches up, per second
+    decay: f32,
+    // corrections bigger than this are not smoothed, the player was teleported or respawned
+    teleport_distance: f32,
+}
+
+impl Default for CorrectionSmoothing {
+    fn default() -> Self {
+        Self {
+            decay: 15.,
+            teleport_distance: 3.,
+        }
+    }
+}
+
+/// the child of the controlled player that holds its mesh and camera, its translation is the part of corrections that wasnt caught up yet
 #[derive(Component)]
-struct Past(RingBuf<TimeStamp>);
+struct PlayerVisual(Entity);
+
+/// how far the prediction was off from the server, shown in the net graph
+#[derive(Resource, Debug, Default)]
+struct PredictionErrors {
+    corrections: u32,
+    teleports: u32,
+    // distance between the predicted and the servers position of the acked input
+    last: f32,
+    max: f32,
+    // moving average over the latest corrections
+    average: f32,
+}
 
-#[derive(Debug, Clone)]
-struct TimeStamp {
-    unix_time: u64,
-    position: Vec3,
+impl PredictionErrors {
+    fn add(&mut self, error: f32) {
+        self.corrections += 1;
+        self.last = error;
+        self.max = self.max.max(error);
+        self.average += (error - self.average) * 0.1;
+    }
 }
 
 #[derive(Component)]
@@ -60,11 +190,7 @@ impl Default for CameraSensitivity {
 pub struct ClientSocket {
     pub target: String,
     pub socket: UdpSocket,
-    pub buf: [u8; 1000],
-}
-struct ReliablePackage {
-    bytes: [u8; 1000],
-    last_send: std::time::Instant,
+    pub buf: [u8; MAX_DATAGRAM_SIZE],
 }
 
 impl ClientSocket {
@@ -73,15 +199,15 @@ impl ClientSocket {
         socket.set_nonblocking(true).unwrap();
         Self {
             socket,
-            buf: [0; 1000],
+            buf: [0; MAX_DATAGRAM_SIZE],
             target,
         }
     }
-    pub fn send(&self, bytes: &[u8], byte_count: &mut usize) -> bool {
+    pub fn send(&self, bytes: &[u8], stats: &mut NetStatsCollector) -> bool {
         match self.socket.send_to(bytes, &self.target) {
             Ok(l) => {
                 let r = l == bytes.len();
-                if r {*byte_count += l;}
+                if r {stats.sent(l);}
                 else {info!("nope");}
                 r
             },
@@ -97,99 +223,73 @@ fn main() {
 
     let (incoming_sender, incoming_receiver) = crossbeam::channel::unbounded::<ServerMessage>();
     let (outgoing_sender, outgoing_receiver) = crossbeam::channel::unbounded::<ClientMessage>();
+    let (stats_sender, stats_receiver) = crossbeam::channel::unbounded::<NetStats>();
 
     let _network_thread = std::thread::spawn(move || {
         let mut client_socket = ClientSocket::new(server_address);
-        let mut delay_pool: Vec<(f32, ServerMessage)> = Vec::with_capacity(1000);
-        let mut past = std::time::Instant::now();
-        let mut last_sent_bytes = std::time::Instant::now();
-        let mut last_received_bytes = std::time::Instant::now();
+        // simulated network conditions, off unless NET_PROFILE is set
+        let link_profile = LinkProfile::from_env();
+        let mut outgoing_link = LinkConditioner::<Vec<u8>>::new(link_profile);
+        let mut incoming_link = LinkConditioner::<Vec<u8>>::new(link_profile);
+        let mut stats = NetStatsCollector::new(std::time::Instant::now());
 
-        let mut reliable_counter = 1;
-        let mut reliable_packages = HashMap::<usize, ReliablePackage>::new();
-        
-        let mut sent_byte_count: usize = 0;
-        let mut received_byte_count: usize = 0;
+        let mut connection = Connection::<ServerMessage>::new(std::time::Instant::now());
 
         loop {
-            sent_byte_count = 0;
-            received_byte_count = 0;
-
-            // delta time
             let present = std::time::Instant::now();
-            let delta_secs = present.duration_since(past).as_secs_f32();
-            past = present;
-
-            let delta_secs_last_sent_bytes = present.duration_since(last_sent_bytes).as_secs_f32();
-            let delta_secs_last_received_bytes = present.duration_since(last_received_bytes).as_secs_f32();
 
-            // resend all important messegaes if they werent confirmed yet
+            // resend all important messegaes if they werent acked yet, also send acks if there was nothing to send
             let now = present;
-            for (_, packet) in reliable_packages.iter_mut() {
-                if now.duration_since(packet.last_send) > std::time::Duration::from_millis(300) {
-                    client_socket.send(&packet.bytes, &mut sent_byte_count);
-                    packet.last_send = now;
-                }
+            for bytes in connection.update(now) {
+                outgoing_link.push(bytes, now);
             }
 
             // get from game
             while let Ok(mut outgoing_package) = outgoing_receiver.try_recv() {
-                if outgoing_package.reliable > 0 {
-                    outgoing_package.reliable = reliable_counter;
+                let reliable = outgoing_package.reliable > 0;
+                if reliable {
+                    outgoing_package.reliable = connection.next_reliable_id(outgoing_package.ordered);
                 }
-                let bytes = outgoing_package.encode();
-                if outgoing_package.reliable > 0 {
-                    reliable_packages.insert(reliable_counter, ReliablePackage {
-                        bytes,
-                        last_send: now,
-                    });
-                    reliable_counter += 1;
+                let Some(datagrams) = connection.send(outgoing_package.encode(), reliable, now) else {
+                    println!("message too big to be sent, dropping it");
+                    continue;
+                };
+                for bytes in datagrams {
+                    outgoing_link.push(bytes, now);
                 }
-                client_socket.send(&bytes, &mut sent_byte_count);
+            }
+
+            for bytes in outgoing_link.poll(now) {
+                client_socket.send(&bytes, &mut stats);
             }
 
             // get from socket
             let ClientSocket { socket, buf, target: _ } = &mut client_socket;
 
             while let Ok((len, _addr)) = socket.recv_from(buf) {
-                if let Some(ServerMessage {reliable, message: server_message}) = ServerMessage::decode(&buf[..len]) {
-                    received_byte_count += len;
-                    if let ServerMessageInner::Confirm(reliable) = &server_message {
-                        reliable_packages.remove(reliable);
-                    }
-                    // incoming_sender.send(server_message);
-                    delay_pool.push((0.0, ServerMessage {reliable, message: server_message}));
-                }
-                else {
-                    println!("got something that couldnt be decoded");
-                }
+                stats.received(len);
+                incoming_link.push(buf[..len].to_vec(), present);
             }
 
-            // go through delay pool
-            let mut removed = Vec::<ServerMessage>::new();
-            delay_pool.retain_mut(|(d, sm)| {
-                *d += delta_secs;
-                if *d >= 0.1 { // TODO do something cool with that delay
-                    removed.push(sm.clone());
-                    false
+            for datagram in incoming_link.poll(present) {
+                // handles the acks, returns something once all fragments of a message are there
+                let Some(bytes) = connection.receive(&datagram, present) else {
+                    continue;
+                };
+                if let Some(server_message) = ServerMessage::decode(&bytes) {
+                    // drops resent duplicates and holds back ordered messages until the gaps are filled
+                    let ServerMessage {reliable, ordered, ..} = server_message;
+                    for server_message in connection.receiver.receive(reliable, ordered, server_message) {
+                        incoming_sender.send(server_message).unwrap();
+                    }
                 }
                 else {
-                    true
+                    println!("got something that couldnt be decoded");
                 }
-            });
-
-            for server_message in removed {
-                incoming_sender.send(server_message).unwrap();
             }
 
-            // print bytes per second
-            if sent_byte_count > 0 && delta_secs_last_sent_bytes != 0. {
-                // info!("upload per second: {}", sent_byte_count as f32 / delta_secs_last_sent_bytes / 1000000.);
-                last_sent_bytes = present;
-            }
-            if received_byte_count > 0 && delta_secs_last_received_bytes != 0. {
-                // info!("download per second: {}", received_byte_count as f32 / delta_secs_last_received_bytes / 1000000.);
-                last_received_bytes = present;
+            if let Some(net_stats) = stats.collect(present, std::iter::once(&mut connection)) {
+                stats_sender.send(net_stats).unwrap();
             }
 
             std::thread::sleep(std::time::Duration::from_millis(1));
@@ -199,31 +299,54 @@ fn main() {
     App::new()
         .insert_resource(IncomingReceiver(incoming_receiver))
         .insert_resource(OutgoingSender(outgoing_sender))
+        .insert_resource(NetStatsReceiver(stats_receiver))
+        .insert_resource(NetStats::default())
         .insert_resource(CursorPos(Vec2::ZERO))
         .insert_resource(EntityMap::default())
         .insert_resource(NetIDMap::default())
-        .insert_resource(NewestPositionUpdateUnixTime(0))
+        .insert_resource(ReceivedSnapshots::default())
+        .insert_resource(InputState::default())
+        .insert_resource(AuthoritativeState::default())
+        .insert_resource(MovementConfig::default())
+        .insert_resource(InterpolationConfig::from_env())
+        .insert_resource(InterpolationClock::default())
+        .insert_resource(CorrectionSmoothing::default())
+        .insert_resource(PredictionErrors::default())
+        .insert_resource(ServerClock::default())
+        .insert_resource(ChallengeToken::default())
         .insert_resource(Gravity(Vec3::NEG_Z * 19.))
         // .insert_resource(Gravity::ZERO)
         .add_plugins(DefaultPlugins)
-        .add_plugins(UpdatePastPlugin)
-        .add_plugins(UnixTimePlugin)
-        .add_plugins(PhysicsPlugins::default())
+        // runs at the default rate until the server tells its own
+        .add_plugins(TickPlugin(TickRate::default()))
+        .add_plugins(PhysicsPlugins::new(FixedPostUpdate))
         .add_systems(Startup, (
             setup,
             spawn_walls,
             cursor_lock,
             spawn_crosshair,
+            spawn_net_graph,
         ))
         .add_systems(Update, (
-            receive_messages,
+            (receive_messages, interpolate_remote_entities).chain(),
+            smooth_corrections,
             cursor_position_system,
             rotate_player,
             player_movement_system,
             update_dead_color,
             player_shoot_system,
+            despawn_shot_effects,
+            send_pings,
+            resend_login,
+            receive_net_stats,
+            (toggle_net_graph, update_net_graph).chain(),
         ))
+        .add_systems(FixedUpdate, (reconcile, send_inputs, predict_movement).chain())
+        .add_systems(Last, send_disconnect_on_exit)
         .run();
+
+    // give the network thread a moment to get the disconnect out
+    std::thread::sleep(std::time::Duration::from_millis(50));
 }
 
 fn setup(
@@ -240,7 +363,8 @@ fn setup(
     commands.insert_resource(PlayerMaterials { normal, destroyed });
 
     let login_message = ClientMessage::login();
-    outgoing_sender.0.send(login_message).unwrap();
+    outgoing_sender.0.send(login_message.clone()).unwrap();
+    commands.insert_resource(PendingLogin(Some(login_message)));
 
     commands.spawn((
         ColliderConstructorHierarchy::new(ColliderConstructor::TrimeshFromMesh),
@@ -301,60 +425,20 @@ fn cursor_position_system(
 
 fn player_movement_system(
     keyboard: Res<ButtonInput<KeyCode>>,
-    rotation_query: Single<(&ChildOf, &Transform), With<CameraSensitivity>>,
-    mut player_query: Query<(Entity, &mut LinearVelocity, &Health, &Transform), (With<Player>, With<Controlled>)>,
-    outgoing_sender: Res<OutgoingSender>,
-    net_id_map: Res<NetIDMap>,
+    mut input_state: ResMut<InputState>,
 ) {
-    let speed = 8.0;
-    let camera_transform = rotation_query.1;
-
-    for (player_entity, mut velocity, health, _transform) in player_query.iter_mut() {
-        let (yaw, _pitch, _roll) = camera_transform.rotation.to_euler(EulerRot::ZXY);
-
-        let yaw_rotation = Quat::from_axis_angle(Vec3::Z, yaw);
-
-        let forward = yaw_rotation * Vec3::Y;
-        let forward_2d = Vec2::new(forward.x, forward.y).normalize_or_zero();
-
-        let right_2d = Vec2::new(-forward_2d.y, forward_2d.x);
-        let net_id = net_id_map.0.get(&player_entity).unwrap();
-
-        if health.0 != 0. {
-            let mut dir = Vec2::ZERO;
-
-            if keyboard.pressed(KeyCode::KeyW) { dir += forward_2d; }
-            if keyboard.pressed(KeyCode::KeyS) { dir -= forward_2d; }
-            if keyboard.pressed(KeyCode::KeyA) { dir += right_2d; }
-            if keyboard.pressed(KeyCode::KeyD) { dir -= right_2d; }
-            if keyboard.just_pressed(KeyCode::Space) {
-                // TODO FIXME jump not predicted
-                velocity.0.z = 10.;
-                outgoing_sender.0.send(ClientMessage::jump(*net_id)).unwrap();
-            }
-
-            if dir.length_squared() > 0.0 {
-                dir = dir.normalize();
-            }
-
-            velocity.0.x = dir.x * speed;;
-            velocity.0.y = dir.y * speed;;
-        } else {
-            velocity.0.x = 0.;
-            velocity.0.y = 0.;
-        }
-
-        outgoing_sender.0.send(ClientMessage::setvelocity(*net_id, velocity.0.truncate().into())).unwrap();
+    // the movement keys are read by send_inputs every tick, only a press of jump could fall between two ticks
+    if keyboard.just_pressed(KeyCode::Space) {
+        // goes out with the next input
+        input_state.jump = true;
     }
 }
 
 fn rotate_player(
     accumulated_mouse_motion: Res<AccumulatedMouseMotion>,
-    player: Single<(&ChildOf, &mut Transform, &CameraSensitivity)>,
-    outgoing_sender: Res<OutgoingSender>,
-    net_id_map: Res<NetIDMap>,
+    player: Single<(&mut Transform, &CameraSensitivity)>,
 ) {
-    let (player_entity, mut transform, camera_sensitivity) = player.into_inner();
+    let (mut transform, camera_sensitivity) = player.into_inner();
 
     let delta = accumulated_mouse_motion.delta;
 
@@ -370,18 +454,14 @@ fn rotate_player(
 
         let new_rotation = Quat::from_euler(EulerRot::ZXY, yaw, pitch, roll);
         transform.rotation = new_rotation;
-
-        let net_id = net_id_map.0.get(&player_entity.0).expect("no entity found in map");
-        outgoing_sender.0.send(ClientMessage::rotation(*net_id, new_rotation.into())).unwrap();
     }
 }
 
 fn player_shoot_system(
     mouse: Res<ButtonInput<MouseButton>>,
     rotation_query: Single<(&ChildOf, &Transform), With<CameraSensitivity>>,
-    mut player_query: Query<(Entity, &mut LinearVelocity, &Health, &Transform), (With<Player>, With<Controlled>)>,
-    outgoing_sender: Res<OutgoingSender>,
-    net_id_map: Res<NetIDMap>,
+    player_query: Query<(&Health, &Transform), (With<Player>, With<Controlled>)>,
+    mut input_state: ResMut<InputState>,
 
     mut commands: Commands,
     mut meshes: ResMut<Assets<Mesh>>,
@@ -394,27 +474,70 @@ fn player_shoot_system(
     let camera_transform = rotation_query.1;
     let shot_direction = camera_transform.rotation * Vec3::Y;
 
-    for (player_entity, mut velocity, health, transform) in player_query.iter_mut() {
+    for (health, transform) in player_query.iter() {
         if health.0 == 0. {
             continue;
         }
-        let net_id = net_id_map.0.get(&player_entity).unwrap();
 
         let ray_origin = transform.translation;
         let ray_dir = shot_direction.normalize();
         let ray_length = 10.0;
 
-        commands.spawn((
-            Mesh3d(meshes.add(Cuboid::new(0.05, 0.05, ray_length).mesh())),
-            MeshMaterial3d(standard_materials.add(Color::srgb(1., 0., 0.))),
-            Transform::from_translation(ray_origin + ray_dir * ray_length / 2.0)
-                .looking_to(ray_dir, Vec3::Z),
-        ));
+        spawn_tracer(&mut commands, &mut meshes, &mut standard_materials, ray_origin, ray_origin + ray_dir * ray_length, Color::srgb(1., 0., 0.));
 
-        outgoing_sender.0.send(ClientMessage::shoot(*net_id, ( shot_direction ).into())).unwrap();
+        input_state.fire = Some((shot_direction, camera_transform.rotation));
     }
 }
 
+/// turns what the player did since the last tick into an input and sends it with the ones the server didnt ack yet
+fn send_inputs(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    camera_query: Single<&Transform, With<CameraSensitivity>>,
+    player_query: Query<(), (With<Player>, With<Controlled>)>,
+    outgoing_sender: Res<OutgoingSender>,
+    mut input_state: ResMut<InputState>,
+    (interpolation_clock, interpolation_config, tick_rate): (Res<InterpolationClock>, Res<InterpolationConfig>, Res<TickRate>),
+) {
+    // nothing to control before the login went through
+    if player_query.is_empty() {
+        return;
+    }
+
+    let mut axes = Vec2::ZERO;
+    if keyboard.pressed(KeyCode::KeyW) { axes.y += 1.; }
+    if keyboard.pressed(KeyCode::KeyS) { axes.y -= 1.; }
+    if keyboard.pressed(KeyCode::KeyD) { axes.x += 1.; }
+    if keyboard.pressed(KeyCode::KeyA) { axes.x -= 1.; }
+    // diagonals arent faster, the server doesnt take axes longer than 1
+    let axes = axes.clamp_length_max(1.);
+
+    let sequence = input_state.next_sequence;
+    input_state.next_sequence = sequence.wrapping_add(1);
+    let fire = input_state.fire.take();
+    let input = InputCommand {
+        sequence,
+        axes: axes.into(),
+        jump: std::mem::take(&mut input_state.jump),
+        fire: fire.map(|(direction, _)| direction.into()),
+        // a shot carries the look it was fired with, the server checks the direction against it
+        look: fire.map_or(camera_query.rotation, |(_, look)| look).into(),
+        // what the other players were shown at, the server rewinds shots to it
+        // before the first snapshot nothing is shown, the server clamps the 0 to the oldest tick it can rewind to
+        view_tick: interpolation_clock
+            .render_tick(&interpolation_config, &tick_rate)
+            .map_or(0, |render_tick| render_tick.max(0.) as TickType),
+    };
+
+    input_state.unacked.push_back(input);
+    if input_state.unacked.len() > InputState::MAX_UNACKED {
+        input_state.unacked.pop_front();
+    }
+
+    let skip = input_state.unacked.len().saturating_sub(INPUT_REDUNDANCY);
+    let inputs = input_state.unacked.iter().skip(skip).copied().collect();
+    outgoing_sender.0.send(ClientMessage::input(inputs)).unwrap();
+}
+
 fn receive_messages(
     incoming_receiver: Res<IncomingReceiver>,
     outgoing_sender: Res<OutgoingSender>,
@@ -423,30 +546,25 @@ fn receive_messages(
     mut standard_materials: ResMut<Assets<StandardMaterial>>,
     mut entity_map: ResMut<EntityMap>,
     mut net_id_map: ResMut<NetIDMap>,
-    mut transform_query: Query<(Entity, &mut Transform, Has<Controlled>, Option<&mut Past>)>,
-    mut anchor_query: Query<(Entity, &PlayerLookAnchor)>,
-    mut velocity_query: Query<(Entity, &mut LinearVelocity, Has<Controlled>)>,
+    controlled_query: Query<(), With<Controlled>>,
+    mut interpolation_query: Query<&mut InterpolationBuffer>,
     mut health_query: Query<(Entity, &mut Health)>,
-    unix_time: Res<UnixTime>,
-    mut newest_position_update_unix_time: ResMut<NewestPositionUpdateUnixTime>,
+    (mut tick_rate, mut fixed_time): (ResMut<TickRate>, ResMut<Time<Fixed>>),
+    mut server_clock: ResMut<ServerClock>,
+    mut pending_login: ResMut<PendingLogin>,
+    mut challenge_token: ResMut<ChallengeToken>,
+    (mut received_snapshots, mut input_state, mut authoritative_state, mut interpolation_clock): (ResMut<ReceivedSnapshots>, ResMut<InputState>, ResMut<AuthoritativeState>, ResMut<InterpolationClock>),
 ) {
 
     loop {
         match incoming_receiver.0.try_recv() {
             Ok(ServerMessage {
-                reliable,
                 message,
+                ..
             }) => {
-
-                if reliable > 0 {
-                    outgoing_sender.0.send(ClientMessage::confirm(reliable));
-                }
                 match message {
 
-                    ServerMessageInner::Confirm(_) => {
-                    },
-
-                    ServerMessageInner::SpawnEntities(entity_packages) => {
+                    ServerMessageInner::SpawnEntities { packages: entity_packages, .. } => {
                         for EntityPackage { net_id, components } in entity_packages {
                             if let Some(_) = entity_map.0.get(&net_id) {
                                 // already exists
@@ -457,6 +575,7 @@ fn receive_messages(
                                 for component in components {
                                     component.apply_to(&mut entity, &mut meshes, &mut standard_materials);
                                 }
+                                entity.insert(InterpolationBuffer::default());
 
                                 let id = entity.id();
                                 entity_map.0.insert(net_id, id);
@@ -464,20 +583,61 @@ fn receive_messages(
                             }
                         }
                     },
-                    ServerMessageInner::UpdateEntities(entity_packages) => {
-                        for EntityPackage { net_id, components } in entity_packages {
-                            if let Some(entity) = entity_map.0.get(&net_id) {
-                                if let Ok(mut entity_commands) = commands.get_entity(*entity) {
-                                    for component in components {
-                                        component.apply_to(&mut entity_commands, &mut meshes, &mut standard_materials);
-                                    }
+
+                    ServerMessageInner::DespawnEntities { net_ids, .. } => {
+                        for net_id in net_ids {
+                            if let Some(entity) = entity_map.0.remove(&net_id) {
+                                net_id_map.0.remove(&entity);
+                                if let Ok(mut entity_commands) = commands.get_entity(entity) {
+                                    entity_commands.despawn();
                                 }
                             }
                         }
                     },
 
+                    ServerMessageInner::Pong { client_time, server_time } => {
+                        server_clock.add_sample(client_time, server_time, unix_millis());
+                    },
+
+                    ServerMessageInner::ShotResult { hit, damage, kill, .. } => {
+                        if hit.is_some() {
+                            spawn_hit_marker(&mut commands, damage, kill);
+                        }
+                    },
+
+                    ServerMessageInner::Shot { origin, end, .. } => {
+                        spawn_tracer(&mut commands, &mut meshes, &mut standard_materials, origin.into(), end.into(), Color::srgb(1., 0.8, 0.));
+                    },
+
+                    ServerMessageInner::Challenge(token) => {
+                        // a late challenge after the login went through is ignored
+                        if pending_login.0.is_some() {
+                            challenge_token.0 = Some(token);
+                            let response = ClientMessage::challenge_response(token);
+                            outgoing_sender.0.send(response.clone()).unwrap();
+                            pending_login.0 = Some(response);
+                        }
+                    },
+
+                    ServerMessageInner::Rejected(reason) => {
+                        pending_login.0 = None;
+                        println!("server rejected the login: {:?}", reason);
+                        commands.write_message(AppExit::error());
+                    },
+
+                    ServerMessageInner::Kicked(reason) => {
+                        println!("kicked by the server: {}", reason);
+                        commands.write_message(AppExit::error());
+                    },
+
                     // receiv myself
-                    ServerMessageInner::Ok(net_id) => {
+                    ServerMessageInner::Ok { net_id, tick_rate: server_tick_rate } => {
+                        pending_login.0 = None;
+                        if *tick_rate != TickRate(server_tick_rate) {
+                            *tick_rate = TickRate(server_tick_rate);
+                            fixed_time.set_timestep_hz(server_tick_rate as f64);
+                        }
+
                         if !entity_map.0.contains_key(&net_id) {
                             println!("player was created successfully with id {:?}", net_id);
 
@@ -545,145 +705,117 @@ fn receive_messages(
                                 ],
                             )).id();
 
+                            // mesh and camera, offset from the player while a correction is smoothed out
+                            let visual_entity = commands.spawn((
+                                Transform::default(),
+                                Visibility::default(),
+                                children![
+
+                                    (
+                                        MeshMaterial3d(standard_materials.add(Color::srgb(0., 1., 0.))),
+                                        Mesh3d(meshes.add(Capsule3d::new(0.4, player_radius))),
+                                        Transform::from_rotation(Quat::from_rotation_x(90_f32.to_radians())),
+                                    ),
+
+                                ],
+                            )).id();
+                            commands.entity(visual_entity).add_child(look_anchor_entity);
+
                             let id = commands.spawn((
                                 Transform::default(),
                                 Player,
                                 PlayerLookAnchor(look_anchor_entity),
+                                PlayerVisual(visual_entity),
                                 Health(100.),
                                 Radius(player_radius),
                                 Controlled,
-                                Past(RingBuf::new(10)),
+                                PredictionHistory::default(),
+                                MovementVelocity::default(),
 
-                                LinearVelocity(Vec3::ZERO),
-                                RigidBody::Dynamic,
+                                // moved by the prediction instead of the physics, so inputs can be replayed
+                                RigidBody::Kinematic,
                                 CollisionLayers::new([Layer::Player], [Layer::Boundary]),
                                 Collider::capsule(0.4, player_radius),
                                 LockedAxes::ROTATION_LOCKED,
-                                SweptCcd::default(),
-
-                                children![
-
-                                    (
-                                        MeshMaterial3d(standard_materials.add(Color::srgb(0., 1., 0.))),
-                                        Mesh3d(meshes.add(Capsule3d::new(0.4, player_radius))),
-                                        Transform::from_rotation(Quat::from_rotation_x(90_f32.to_radians())),
-                                    ),
-
-                                ],
                             )).id();
 
-                            commands.entity(id).add_child(look_anchor_entity);
+                            commands.entity(id).add_child(visual_entity);
 
                             entity_map.0.insert(net_id, id);
                             net_id_map.0.insert(id, net_id);
                         }
                     },
 
-                    ServerMessageInner::UpdatePlayerLooks(packages) => {
-                        // FIXME its setting the rotation but nothing visible
-                        for package in packages {
-                            if let Some(player_entity) = entity_map.0.get(&package.net_id) {
-                                let anchor = if let Ok(anchor) = anchor_query.get(*player_entity) { anchor } else {continue;};
-                                let entity = anchor.0;
-                                if let Ok((_, mut transform, controlled, _)) = transform_query.get_mut(entity) {
-                                    if !controlled {
-                                        transform.rotation = package.rotation.clone().into();
-                                    }
-                                }
-                            }
+                    ServerMessageInner::Snapshot{sequence, baseline, tick: message_tick, input_ack, part, parts, entities} => {
+                        // ignore older snapshots, the parts of the newest one are put together
+                        if part >= parts || received_snapshots.newest.is_some_and(|newest| sequence_newer(newest, sequence)) {
+                            continue;
                         }
-                    },
-
-                    ServerMessageInner::UpdatePositions{unix_time: message_unix_time, packages} => {
-                        // ignore older position updates
-                        if message_unix_time <= newest_position_update_unix_time.0 {
+                        let baseline_snapshot = match baseline {
+                            Some(baseline) => match received_snapshots.history.get(baseline) {
+                                Some(baseline_snapshot) => Some(baseline_snapshot),
+                                // the baseline was already forgotten, the server falls back to a full snapshot once it notices
+                                None => continue,
+                            },
+                            None => None,
+                        };
+                        let Some(snapshot) = Snapshot::apply(baseline_snapshot, entities) else {
                             continue;
+                        };
+                        if received_snapshots.newest != Some(sequence) {
+                            received_snapshots.newest = Some(sequence);
+                            received_snapshots.parts.clear();
+                            received_snapshots.partial = Snapshot::default();
+                            interpolation_clock.received(message_tick);
                         }
-                        // important to consider the latency because latency means the server also processed the player movement later, so the prediction also pretents that the messages received by the server lay behind by the latency too. it is to be assmued that the latency from client to server is the same as server to client, because what we actually need is the client to server latency due to the server calculating player inputs delayed, which is why the client takes the server messages as delayed too for client prediction.
-                        let unix_delta = unix_time.0 - message_unix_time;
-                        let considered_message_unix_time = message_unix_time - unix_delta;
-                        newest_position_update_unix_time.0 = message_unix_time;
-
-                        for position_package in packages {
-                            if let Some(entity) = entity_map.0.get(&position_package.net_id) {
-                                if let Ok((_, mut transform, controlled, past_option)) = transform_query.get_mut(*entity) {
-                                    // if the entity has past storage (which is only the client itself because of client prediction)
-                                    if let Some(mut past) = past_option {
-                                        // get the older and newer timestamps from the past, interpolate the position to the received message timestamp and calculate the difference between that position and the position in the received message. that is the ammount that the past was wrongly calculated and needs to be fixed now (add diff to current pos)
-                                        let older_data_option = past.0
-                                            .iter()
-                                            .enumerate()
-                                            .find(|(i, time_stamp)| time_stamp.unix_time <= considered_message_unix_time)
-                                            .map(|(idx, ts)| (idx, ts.clone()));
-
-                                        if let Some(( older_index, older_time_stamp )) = older_data_option {
-                                            // there can be a case where the past doesnt have a newer timestamp. if so, just take the present and interpolate between older timestamp and present
-                                            let newer_time_stamp =
-                                                if older_index > 0 {
-                                                    past.0.get(older_index - 1).unwrap().clone()
-                                                }
-                                                else {
-                                                    TimeStamp {
-                                                        unix_time: unix_time.0,
-                                                        position: transform.translation,
-                                                    }
-                                                }
-                                            ;
-
-                                            let time_diff = (newer_time_stamp.unix_time - older_time_stamp.unix_time) as f32;
-
-                                            let lerp_t = if time_diff > 0.0 {
-                                                (considered_message_unix_time - older_time_stamp.unix_time) as f32 / time_diff
-                                            } else {
-                                                0.0
-                                            };
-
-                                            let message_position: Vec3 = position_package.position.clone().into();
-                                            let past_position = older_time_stamp.position
-                                                .lerp(newer_time_stamp.position, lerp_t);
-                                            let applied_correction = message_position - past_position;
-
-                                            for i in 0..=older_index {
-                                                if let Some(timestamp) = past.0.get_mut(i) {
-                                                    timestamp.position += applied_correction;
-                                                }
-                                            }
-                                            transform.translation.x += applied_correction.x;
-                                            transform.translation.y += applied_correction.y;
-                                            transform.translation.z += applied_correction.z;
-                                            info!("applied correction: {:?}", applied_correction);
-                                            info!("position package: {:?}", position_package.position);
-                                            // toggle here to enable / disable prediction
-                                            // transform.translation = position_package.position.clone().into();
-                                        }
-                                        else {
-                                            transform.translation = position_package.position.clone().into();
-                                        }
-                                    }
-                                    else {
-                                        transform.translation = position_package.position.clone().into();
-                                    }
-                                    if !controlled {
-                                        transform.rotation = position_package.rotation.clone().into();
-                                    }
-                                }
-                            }
+                        if !received_snapshots.parts.insert(part) {
+                            continue;
+                        }
+                        if let Some(input_ack) = input_ack {
+                            input_state.unacked.retain(|input| sequence_newer(input.sequence, input_ack));
                         }
-                    },
 
-                    ServerMessageInner::UpdateVelocities(velocity_packages) => {
-                        for package in velocity_packages {
-                            if let Some(entity) = entity_map.0.get(&package.net_id) {
-                                if let Ok((_, mut velocity, controlled)) = velocity_query.get_mut(*entity) {
-                                    if !controlled {
-                                        velocity.0 = package.velocity.into();
-                                    }
+                        let previous_snapshot = received_snapshots.history.latest().map(|(_, previous)| previous);
+                        for (net_id, state) in snapshot.entities.iter() {
+                            let Some(&entity) = entity_map.0.get(net_id) else {
+                                continue;
+                            };
+
+                            if controlled_query.contains(entity) {
+                                // predicted, reconcile compares it with what was predicted for the same input
+                                // this has to happen even if the state didnt change, the prediction could have moved
+                                if let Some(input_ack) = input_ack {
+                                    authoritative_state.0 = Some(ServerPlayerState {
+                                        input_ack,
+                                        state: MovementState {
+                                            position: state.position.into(),
+                                            velocity: state.velocity.into(),
+                                        },
+                                    });
                                 }
+                                continue;
+                            }
+
+                            // an unchanged state is either standing still or held back by the server, the entity moves on from its last sample
+                            if previous_snapshot.and_then(|previous| previous.entities.get(net_id)) == Some(state) {
+                                continue;
+                            }
+
+                            if let Ok(mut interpolation_buffer) = interpolation_query.get_mut(entity) {
+                                interpolation_buffer.push(InterpolationSample::new(message_tick, state));
                             }
                         }
+
+                        received_snapshots.partial.entities.extend(snapshot.entities);
+                        // only whole snapshots can be baselines, so only those are acked
+                        if received_snapshots.parts.len() == parts as usize {
+                            let complete = std::mem::take(&mut received_snapshots.partial);
+                            received_snapshots.history.insert(sequence, complete);
+                            outgoing_sender.0.send(ClientMessage::snapshot_ack(sequence)).unwrap();
+                        }
                     },
 
-                    ServerMessageInner::UpdateHealths(packages) => {
+                    ServerMessageInner::UpdateHealths { packages, .. } => {
                         for package in packages {
                             if let Some(entity) = entity_map.0.get(&package.net_id) {
                                 if let Ok((_, mut health)) = health_query.get_mut(*entity) {
@@ -703,6 +835,50 @@ fn receive_messages(
     }
 }
 
+fn send_pings(
+    outgoing_sender: Res<OutgoingSender>,
+    mut since_last_ping: Local<f32>,
+    time: Res<Time>,
+) {
+    *since_last_ping += time.delta_secs();
+    if *since_last_ping < ServerClock::PING_INTERVAL {
+        return;
+    }
+    *since_last_ping = 0.;
+    outgoing_sender.0.send(ClientMessage::ping(unix_millis())).unwrap();
+}
+
+fn resend_login(
+    outgoing_sender: Res<OutgoingSender>,
+    pending_login: Res<PendingLogin>,
+    mut since_last_send: Local<f32>,
+    time: Res<Time>,
+) {
+    let Some(message) = &pending_login.0 else {
+        return;
+    };
+    *since_last_send += time.delta_secs();
+    if *since_last_send < PendingLogin::RESEND_INTERVAL {
+        return;
+    }
+    *since_last_send = 0.;
+    outgoing_sender.0.send(message.clone()).unwrap();
+}
+
+fn send_disconnect_on_exit(
+    mut app_exit: MessageReader<AppExit>,
+    outgoing_sender: Res<OutgoingSender>,
+    challenge_token: Res<ChallengeToken>,
+) {
+    // without a challenge the server has nothing to forget yet
+    let Some(token) = challenge_token.0 else {
+        return;
+    };
+    if app_exit.read().next().is_some() {
+        outgoing_sender.0.send(ClientMessage::disconnect(token)).unwrap();
+    }
+}
+
 fn cursor_lock(
     mut cursor_options: Single<&mut CursorOptions, With<PrimaryWindow>>,
 ) {
@@ -713,10 +889,11 @@ fn cursor_lock(
 // TODO figure out why this only works without the player componnent
 fn update_dead_color(
     mut materials: ResMut<Assets<StandardMaterial>>,
-    health_q: Query<(Entity, &Health, &Children), Changed<Health>>,
+    health_q: Query<(Entity, &Health), Changed<Health>>,
+    children_q: Query<&Children>,
     material_q: Query<&MeshMaterial3d<StandardMaterial>>,
 ) {
-    for (entity, health, children) in &health_q {
+    for (entity, health) in &health_q {
         let health_percent = (health.0 / 100.0).clamp(0.0, 1.0);
         let color = Color::srgb(
             1.0 - health_percent,
@@ -730,7 +907,8 @@ fn update_dead_color(
             }
         }
 
-        for child in children.iter() {
+        // the mesh of the controlled player sits below its visual
+        for child in children_q.iter_descendants(entity) {
             if let Ok(mat_handle) = material_q.get(child) {
                 if let Some(material) = materials.get_mut(mat_handle.0.id()) {
                     material.base_color = color;
@@ -741,6 +919,81 @@ fn update_dead_color(
 }
 
 // ai generated by claude for testing
+/// how long tracers and hit markers stay visible
+const SHOT_EFFECT_SECS: f32 = 0.2;
+
+/// despawned once the timer ran out
+#[derive(Component)]
+struct ShotEffect(Timer);
+
+fn spawn_tracer(
+    commands: &mut Commands,
+    meshes: &mut ResMut<Assets<Mesh>>,
+    standard_materials: &mut ResMut<Assets<StandardMaterial>>,
+    start: Vec3,
+    end: Vec3,
+    color: Color,
+) {
+    let length = start.distance(end);
+    let Ok(direction) = Dir3::new(end - start) else {
+        return;
+    };
+    commands.spawn((
+        Mesh3d(meshes.add(Cuboid::new(0.05, 0.05, length).mesh())),
+        MeshMaterial3d(standard_materials.add(color)),
+        Transform::from_translation(start + direction * length / 2.0)
+            .looking_to(direction, Vec3::Z),
+        ShotEffect(Timer::from_seconds(SHOT_EFFECT_SECS, TimerMode::Once)),
+    ));
+}
+
+/// an x over the crosshair when the server confirmed a hit, red if it was a kill and grey if it did no damage
+fn spawn_hit_marker(commands: &mut Commands, damage: f32, kill: bool) {
+    let color = if kill {
+        Color::srgb(1., 0., 0.)
+    }
+    else if damage > 0. {
+        Color::WHITE
+    }
+    else {
+        Color::srgb(0.6, 0.6, 0.6)
+    };
+    commands
+        .spawn((
+            Node {
+                width: Val::Percent(100.0),
+                height: Val::Percent(100.0),
+                justify_content: JustifyContent::Center,
+                align_items: AlignItems::Center,
+                position_type: PositionType::Absolute,
+                ..default()
+            },
+            ShotEffect(Timer::from_seconds(SHOT_EFFECT_SECS, TimerMode::Once)),
+        ))
+        .with_children(|parent| {
+            parent.spawn((
+                Text::new("x"),
+                TextFont {
+                    font_size: 32.,
+                    ..default()
+                },
+                TextColor(color),
+            ));
+        });
+}
+
+fn despawn_shot_effects(
+    mut commands: Commands,
+    mut effects: Query<(Entity, &mut ShotEffect)>,
+    time: Res<Time>,
+) {
+    for (entity, mut effect) in &mut effects {
+        if effect.0.tick(time.delta()).is_finished() {
+            commands.entity(entity).despawn();
+        }
+    }
+}
+
 fn spawn_crosshair(mut commands: Commands) {
     commands
         .spawn(Node {
@@ -791,33 +1044,245 @@ fn spawn_crosshair(mut commands: Commands) {
         });
 }
 
-#[derive(Resource)]
-struct LastUpdatePast(f32);
-
-struct UpdatePastPlugin;
-impl Plugin for UpdatePastPlugin {
-    fn build(&self, app: &mut App) {
-        app
-            .insert_resource(LastUpdatePast(0.))
-            .add_systems(Update, update_past)
-        ;
+/// applies the input of this tick to the controlled player and remembers it for replaying
+fn predict_movement(
+    input_state: Res<InputState>,
+    mut player_query: Query<(&mut Transform, &mut MovementVelocity, &mut PredictionHistory, &Collider, &Health), (With<Player>, With<Controlled>)>,
+    movement_config: Res<MovementConfig>,
+    spatial_query: SpatialQuery,
+    gravity: Res<Gravity>,
+    time: Res<Time>,
+) {
+    let Some(input) = input_state.unacked.back() else {
+        return;
+    };
+    let movement = MovementStep {
+        config: &movement_config,
+        spatial_query: &spatial_query,
+        gravity: gravity.0,
+        delta_secs: time.delta_secs(),
+    };
+
+    for (mut transform, mut velocity, mut history, collider, health) in &mut player_query {
+        // no new input this tick
+        if history.0.back().is_some_and(|predicted| predicted.input.sequence == input.sequence) {
+            continue;
+        }
+
+        let state = movement.step(collider, MovementState { position: transform.translation, velocity: velocity.0 }, input, health.0 != 0.);
+        transform.translation = state.position;
+        velocity.0 = state.velocity;
+
+        history.0.push_back(PredictedTick {
+            input: *input,
+            state,
+        });
+        if history.0.len() > PREDICTION_HISTORY {
+            history.0.pop_front();
+        }
     }
 }
 
-fn update_past(
-    mut past_q: Query<( &mut Past, &Transform )>,
-    mut last_update_past: ResMut<LastUpdatePast>,
+/// compares the servers state with what was predicted for the same input
+/// if they differ the player is put back to the servers state and every input after it is simulated again
+fn reconcile(
+    mut authoritative_state: ResMut<AuthoritativeState>,
+    mut player_query: Query<(&mut Transform, &mut MovementVelocity, &mut PredictionHistory, &Collider, &Health, &PlayerVisual), (With<Player>, With<Controlled>)>,
+    mut visual_query: Query<&mut Transform, Without<Controlled>>,
+    correction_smoothing: Res<CorrectionSmoothing>,
+    mut prediction_errors: ResMut<PredictionErrors>,
+    movement_config: Res<MovementConfig>,
+    spatial_query: SpatialQuery,
+    gravity: Res<Gravity>,
     time: Res<Time>,
-    unix_time: Res<UnixTime>,
 ) {
-    last_update_past.0 += time.delta_secs();
-    if last_update_past.0 < 0.1 {return;}
-    last_update_past.0 = 0.;
-
-    for (mut past, transform) in &mut past_q {
-        past.0.push(TimeStamp {
-            unix_time: unix_time.0,
-            position: transform.translation.clone(),
-        });
+    let Some(server_state) = authoritative_state.0.take() else {
+        return;
+    };
+    let movement = MovementStep {
+        config: &movement_config,
+        spatial_query: &spatial_query,
+        gravity: gravity.0,
+        delta_secs: time.delta_secs(),
+    };
+
+    for (mut transform, mut velocity, mut history, collider, health, visual) in &mut player_query {
+        let acked_index = history.0.iter().position(|predicted| predicted.input.sequence == server_state.input_ack);
+
+        // everything up to the acked input is confirmed now
+        if let Some(index) = acked_index {
+            let predicted = history.0[index];
+            history.0.drain(..=index);
+            let error = predicted.state.position.distance(server_state.state.position);
+            // a jump the server didnt allow shows up in the velocity before the position moved much
+            if error < RECONCILE_EPSILON
+                && predicted.state.velocity.distance(server_state.state.velocity) < RECONCILE_EPSILON {
+                continue;
+            }
+            prediction_errors.add(error);
+        }
+        else if history.0.front().is_some_and(|predicted| sequence_newer(predicted.input.sequence, server_state.input_ack)) {
+            // an older ack that arrived late, already reconciled against a newer one
+            continue;
+        }
+        else {
+            // the history doesnt reach back that far, nothing to replay
+            history.0.clear();
+        }
+
+        let mut state = server_state.state;
+        for predicted in history.0.iter_mut() {
+            state = movement.step(collider, state, &predicted.input, health.0 != 0.);
+            predicted.state = state;
+        }
+
+        // the visual stays where it was and catches up in smooth_corrections
+        let correction = transform.translation - state.position;
+        if let Ok(mut visual_transform) = visual_query.get_mut(visual.0) {
+            if correction.length() > correction_smoothing.teleport_distance {
+                visual_transform.translation = Vec3::ZERO;
+                prediction_errors.teleports += 1;
+            }
+            else {
+                visual_transform.translation += correction;
+            }
+        }
+
+        transform.translation = state.position;
+        velocity.0 = state.velocity;
+    }
+}
+
+/// moves the visual of the controlled player back onto its actual position after a correction
+fn smooth_corrections(
+    correction_smoothing: Res<CorrectionSmoothing>,
+    player_query: Query<&PlayerVisual, With<Controlled>>,
+    mut visual_query: Query<&mut Transform, Without<Controlled>>,
+    time: Res<Time>,
+) {
+    for visual in &player_query {
+        let Ok(mut visual_transform) = visual_query.get_mut(visual.0) else {
+            continue;
+        };
+        visual_transform.translation *= (-correction_smoothing.decay * time.delta_secs()).exp();
+        if visual_transform.translation.length() < 0.001 {
+            visual_transform.translation = Vec3::ZERO;
+        }
+    }
+}
+
+/// shows remote entities a bit behind the newest snapshot, between the two states around that time
+fn interpolate_remote_entities(
+    mut interpolation_clock: ResMut<InterpolationClock>,
+    interpolation_config: Res<InterpolationConfig>,
+    tick_rate: Res<TickRate>,
+    time: Res<Time>,
+    mut remote_query: Query<(&mut Transform, &mut InterpolationBuffer, Option<&PlayerLookAnchor>)>,
+    mut anchor_query: Query<&mut Transform, Without<InterpolationBuffer>>,
+) {
+    interpolation_clock.advance(time.delta_secs(), &tick_rate);
+    let Some(render_tick) = interpolation_clock.render_tick(&interpolation_config, &tick_rate) else {
+        return;
+    };
+
+    for (mut transform, mut interpolation_buffer, anchor) in &mut remote_query {
+        let Some(sample) = interpolation_buffer.sample(render_tick, &interpolation_config, &tick_rate) else {
+            continue;
+        };
+        transform.translation = sample.position;
+        transform.rotation = sample.rotation;
+
+        // FIXME its setting the rotation but nothing visible
+        if let (Some(look), Some(anchor)) = (sample.look, anchor) {
+            if let Ok(mut anchor_transform) = anchor_query.get_mut(anchor.0) {
+                anchor_transform.rotation = look;
+            }
+        }
+    }
+}
+
+/// text overlay with the network stats, toggled with F3
+#[derive(Component)]
+struct NetGraph;
+
+fn spawn_net_graph(mut commands: Commands) {
+    commands.spawn((
+        NetGraph,
+        Text::new(""),
+        TextFont {
+            font_size: 14.0,
+            ..default()
+        },
+        TextColor(Color::srgba(1.0, 1.0, 1.0, 0.9)),
+        BackgroundColor(Color::srgba(0.0, 0.0, 0.0, 0.5)),
+        Node {
+            position_type: PositionType::Absolute,
+            top: Val::Px(8.0),
+            left: Val::Px(8.0),
+            padding: UiRect::all(Val::Px(4.0)),
+            ..default()
+        },
+        Visibility::Hidden,
+    ));
+}
+
+fn receive_net_stats(
+    stats_receiver: Res<NetStatsReceiver>,
+    mut net_stats: ResMut<NetStats>,
+) {
+    while let Ok(stats) = stats_receiver.0.try_recv() {
+        *net_stats = stats;
+    }
+}
+
+fn toggle_net_graph(
+    keyboard: Res<ButtonInput<KeyCode>>,
+    mut net_graph: Query<&mut Visibility, With<NetGraph>>,
+) {
+    if !keyboard.just_pressed(KeyCode::F3) {
+        return;
+    }
+    for mut visibility in net_graph.iter_mut() {
+        *visibility = match *visibility {
+            Visibility::Hidden => Visibility::Visible,
+            _ => Visibility::Hidden,
+        };
+    }
+}
+
+fn update_net_graph(
+    net_stats: Res<NetStats>,
+    server_clock: Res<ServerClock>,
+    prediction_errors: Res<PredictionErrors>,
+    mut net_graph: Query<(&mut Text, &Visibility), With<NetGraph>>,
+) {
+    for (mut text, visibility) in net_graph.iter_mut() {
+        if *visibility == Visibility::Hidden {
+            continue;
+        }
+        text.0 = format!(
+            "up   {:.1} kB/s  {:.0} packets/s\n\
+             down {:.1} kB/s  {:.0} packets/s\n\
+             resends {:.1}/s  pending reliable {}\n\
+             rtt {:.0} ms  loss {:.1}%\n\
+             clock rtt {} ms  offset {} ms\n\
+             corrections {}  teleports {}\n\
+             prediction error {:.3} avg {:.3} max {:.3}",
+            net_stats.bytes_up_per_sec / 1000.,
+            net_stats.packets_up_per_sec,
+            net_stats.bytes_down_per_sec / 1000.,
+            net_stats.packets_down_per_sec,
+            net_stats.resends_per_sec,
+            net_stats.pending_reliable,
+            net_stats.rtt_ms,
+            net_stats.loss * 100.,
+            server_clock.rtt_ms,
+            server_clock.offset_ms,
+            prediction_errors.corrections,
+            prediction_errors.teleports,
+            prediction_errors.last,
+            prediction_errors.average,
+            prediction_errors.max,
+        );
     }
 }
@@ -1,10 +1,13 @@
 use std::net::{SocketAddr, UdpSocket};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasher, RandomState};
+use std::time::{Duration, Instant};
+use bevy::ecs::entity::Entities;
 use bevy_royal::*;
 
 pub struct ServerSocket {
     pub socket: UdpSocket,
-    pub buf: [u8; 1000],
+    pub buf: [u8; MAX_DATAGRAM_SIZE],
 }
 
 impl ServerSocket {
@@ -13,14 +16,14 @@ impl ServerSocket {
     ) -> Self {
         Self {
             socket,
-            buf: [0; 1000],
+            buf: [0; MAX_DATAGRAM_SIZE],
         }
     }
-    pub fn send_to(&self, bytes: &[u8], addr: SocketAddr, byte_count: &mut usize) -> bool {
+    pub fn send_to(&self, bytes: &[u8], addr: SocketAddr, stats: &mut NetStatsCollector) -> bool {
         match self.socket.send_to(bytes, addr) {
             Ok(l) => {
                 let r = l == bytes.len();
-                if r {*byte_count += l;}
+                if r {stats.sent(l);}
                 r
             },
             Err(_) => false,
@@ -28,109 +31,286 @@ impl ServerSocket {
     }
 }
 
-struct ReliablePackage {
-    bytes: [u8; 1000],
-    addr: SocketAddr,
-    last_send: std::time::Instant,
+/// counts packets per address that couldnt be decoded, an address that sends too many is ignored for a while
+#[derive(Default)]
+struct MalformedPackets {
+    // how many in the current window, and when the window started
+    counts: HashMap<SocketAddr, (u32, Instant)>,
+    throttled: HashMap<SocketAddr, Instant>,
+}
+
+impl MalformedPackets {
+    const WINDOW: Duration = Duration::from_secs(10);
+    const MAX_PER_WINDOW: u32 = 20;
+    const THROTTLE: Duration = Duration::from_secs(30);
+
+    fn is_throttled(&self, addr: SocketAddr, now: Instant) -> bool {
+        self.throttled.get(&addr).is_some_and(|until| now < *until)
+    }
+
+    fn report(&mut self, addr: SocketAddr, now: Instant) {
+        let (count, since) = self.counts.entry(addr).or_insert((0, now));
+        if now.duration_since(*since) > Self::WINDOW {
+            *count = 0;
+            *since = now;
+        }
+        *count += 1;
+        if *count > Self::MAX_PER_WINDOW {
+            println!("{} sent {} malformed packets, ignoring it for {} s", addr, count, Self::THROTTLE.as_secs());
+            self.counts.remove(&addr);
+            self.throttled.insert(addr, now + Self::THROTTLE);
+        }
+    }
+
+    /// forgets windows and throttles that are over, so spoofed addresses dont pile up
+    fn cleanup(&mut self, now: Instant) {
+        self.counts.retain(|_, (_, since)| now.duration_since(*since) <= Self::WINDOW);
+        self.throttled.retain(|_, until| now < *until);
+    }
+}
+
+/// token buckets for every address, one that keeps running into them gets banned for a while
+struct AddressLimiter {
+    limits: RateLimits,
+    // the packet bucket, the message buckets in the order of MessageClass::ALL, how many were dropped in the current window and when it started
+    addresses: HashMap<SocketAddr, (TokenBucket, [TokenBucket; 4], u32, Instant)>,
+    banned: HashMap<SocketAddr, Instant>,
+}
+
+impl AddressLimiter {
+    const WINDOW: Duration = Duration::from_secs(10);
+    const MAX_DROPPED_PER_WINDOW: u32 = 100;
+    const BAN: Duration = Duration::from_secs(300);
+    /// how long a kicked player cant come back
+    const KICK_BAN: Duration = Duration::from_secs(60);
+
+    fn new(limits: RateLimits) -> Self {
+        Self {
+            limits,
+            addresses: HashMap::new(),
+            banned: HashMap::new(),
+        }
+    }
+
+    fn is_banned(&self, addr: SocketAddr, now: Instant) -> bool {
+        self.banned.get(&addr).is_some_and(|until| now < *until)
+    }
+
+    fn buckets(&mut self, addr: SocketAddr, now: Instant) -> &mut (TokenBucket, [TokenBucket; 4], u32, Instant) {
+        let limits = self.limits;
+        self.addresses
+            .entry(addr)
+            .or_insert_with(|| (TokenBucket::new(limits.packets, now), MessageClass::ALL.map(|class| TokenBucket::new(limits.get(class), now)), 0, now))
+    }
+
+    /// false if the address sends more datagrams than it may, checked before they are decoded
+    fn allow_packet(&mut self, addr: SocketAddr, now: Instant) -> bool {
+        let allowed = self.buckets(addr, now).0.take(now);
+        if !allowed {
+            self.dropped(addr, now);
+        }
+        allowed
+    }
+
+    /// false if the message goes over the limit of its class and has to be dropped
+    fn allow(&mut self, addr: SocketAddr, class: MessageClass, now: Instant) -> bool {
+        let allowed = self.buckets(addr, now).1[class as usize].take(now);
+        if !allowed {
+            self.dropped(addr, now);
+        }
+        allowed
+    }
+
+    fn dropped(&mut self, addr: SocketAddr, now: Instant) {
+        let (_, _, dropped, since) = self.buckets(addr, now);
+        if now.duration_since(*since) > Self::WINDOW {
+            *dropped = 0;
+            *since = now;
+        }
+        *dropped += 1;
+        if *dropped > Self::MAX_DROPPED_PER_WINDOW {
+            println!("{} kept flooding the server, banned for {} s", addr, Self::BAN.as_secs());
+            self.ban(addr, now, Self::BAN);
+        }
+    }
+
+    fn ban(&mut self, addr: SocketAddr, now: Instant, duration: Duration) {
+        self.addresses.remove(&addr);
+        self.banned.insert(addr, now + duration);
+    }
+
+    /// forgets bans that are over and the buckets of addresses that went away, or never got a connection, for a while
+    fn cleanup(&mut self, now: Instant, connections: &HashMap<SocketAddr, Connection<ClientMessage>>) {
+        self.addresses.retain(|addr, (_, _, _, since)| connections.contains_key(addr) || now.duration_since(*since) <= Self::WINDOW);
+        self.banned.retain(|_, until| now < *until);
+    }
+}
+
+/// how many addresses can be in the middle of logging in at once, new ones are ignored until some finish or time out
+const MAX_PENDING_CONNECTIONS: usize = 64;
+
+/// drops the connection of an address that just got banned, the game cleans up after it like after a timeout
+fn remove_banned(addr: SocketAddr, connections: &mut HashMap<SocketAddr, Connection<ClientMessage>>, incoming_sender: &crossbeam::channel::Sender<(SocketAddr, ClientMessage)>, challenge_secret: &ChallengeSecret) {
+    if connections.remove(&addr).is_some() {
+        incoming_sender.send((addr, ClientMessage::disconnect(challenge_secret.token(addr)))).unwrap();
+    }
 }
 
 fn main() {
 
     let (incoming_sender, incoming_receiver) = crossbeam::channel::unbounded::<(SocketAddr, ClientMessage)>();
     let (outgoing_sender, outgoing_receiver) = crossbeam::channel::unbounded::<(SocketAddr, ServerMessage)>();
+    let (stats_sender, stats_receiver) = crossbeam::channel::unbounded::<NetStats>();
+    // the network thread checks the token of disconnects, the game the one of logins
+    let challenge_secret = ChallengeSecret::default();
+    let thread_challenge_secret = challenge_secret.clone();
 
     let _network_thread = std::thread::spawn(move || {
         let socket = UdpSocket::bind("0.0.0.0:7878").unwrap();
         socket.set_nonblocking(true).unwrap();
         let mut server_socket = ServerSocket::new(socket);
-        let mut delay_pool: Vec<(f32, ( SocketAddr, ClientMessage ))> = Vec::with_capacity(1000);
-        let mut past = std::time::Instant::now();
-        let mut last_sent_bytes = std::time::Instant::now();
-        let mut last_received_bytes = std::time::Instant::now();
-
-        let mut reliable_counter = 1;
-        let mut reliable_packages = HashMap::<usize, ReliablePackage>::new();
-
-        let mut sent_byte_count: usize = 0;
-        let mut received_byte_count: usize = 0;
+        // simulated network conditions, off unless NET_PROFILE is set
+        let link_profile = LinkProfile::from_env();
+        let mut outgoing_link = LinkConditioner::<(SocketAddr, Vec<u8>)>::new(link_profile);
+        let mut incoming_link = LinkConditioner::<(SocketAddr, Vec<u8>)>::new(link_profile);
+        let mut stats = NetStatsCollector::new(std::time::Instant::now());
+
+        let mut connections = HashMap::<SocketAddr, Connection<ClientMessage>>::new();
+        // addresses the game let in, everyone else is still logging in
+        let mut logged_in = HashSet::<SocketAddr>::new();
+        let mut malformed_packets = MalformedPackets::default();
+        let mut address_limiter = AddressLimiter::new(RateLimits::from_env());
 
         loop {
-            sent_byte_count = 0;
-            received_byte_count = 0;
-
-            // delta time
             let present = std::time::Instant::now();
 
-            let delta_secs = present.duration_since(past).as_secs_f32();
-            past = present;
+            // resend all important messegaes if they werent acked yet, also send acks if there was nothing to send
+            let now = std::time::Instant::now();
 
-            let delta_secs_last_sent_bytes = present.duration_since(last_sent_bytes).as_secs_f32();
-            let delta_secs_last_received_bytes = present.duration_since(last_received_bytes).as_secs_f32();
+            // forget clients that went quiet, the game cleans up after them like after a normal disconnect
+            connections.retain(|addr, connection| {
+                let alive = now.duration_since(connection.last_receive) < CONNECTION_TIMEOUT;
+                if !alive {
+                    println!("{} timed out", addr);
+                    incoming_sender.send((*addr, ClientMessage::disconnect(thread_challenge_secret.token(*addr)))).unwrap();
+                }
+                alive
+            });
+            logged_in.retain(|addr| connections.contains_key(addr));
 
-            // resend all important messegaes if they werent confirmed yet
-            let now = std::time::Instant::now();
-            for (_, packet) in reliable_packages.iter_mut() {
-                if now.duration_since(packet.last_send) > std::time::Duration::from_millis(300) {
-                    server_socket.send_to(&packet.bytes, packet.addr, &mut sent_byte_count);
-                    packet.last_send = now;
+            for (addr, connection) in connections.iter_mut() {
+                for bytes in connection.update(now) {
+                    outgoing_link.push((*addr, bytes), now);
                 }
             }
 
             // get from game
             while let Ok((addr, mut outgoing_package)) = outgoing_receiver.try_recv() {
-                if outgoing_package.reliable > 0 {
-                    outgoing_package.reliable = reliable_counter;
+                // the client timed out or was removed in the meantime, it doesnt get a connection back
+                let Some(connection) = connections.get_mut(&addr) else {
+                    continue;
+                };
+                if matches!(outgoing_package.message, ServerMessageInner::Ok { .. }) {
+                    logged_in.insert(addr);
                 }
-                let bytes = outgoing_package.encode();
-                if outgoing_package.reliable > 0 {
-                    reliable_packages.insert(reliable_counter, ReliablePackage {
-                        bytes,
-                        addr,
-                        last_send: now,
-                    });
-                    reliable_counter += 1;
+                let reliable = outgoing_package.reliable > 0;
+                if reliable {
+                    outgoing_package.reliable = connection.next_reliable_id(outgoing_package.ordered);
                 }
-                server_socket.send_to(&bytes, addr, &mut sent_byte_count);
+                let Some(datagrams) = connection.send(outgoing_package.encode(), reliable, now) else {
+                    println!("message too big to be sent, dropping it");
+                    continue;
+                };
+                for bytes in datagrams {
+                    outgoing_link.push((addr, bytes), now);
+                }
+                // the game already cleaned up after the player, the kick message is the last thing it gets
+                if matches!(outgoing_package.message, ServerMessageInner::Kicked(_)) {
+                    println!("{} kicked, banned for {} s", addr, AddressLimiter::KICK_BAN.as_secs());
+                    connections.remove(&addr);
+                    logged_in.remove(&addr);
+                    address_limiter.ban(addr, now, AddressLimiter::KICK_BAN);
+                }
+            }
+
+            for (addr, bytes) in outgoing_link.poll(now) {
+                server_socket.send_to(&bytes, addr, &mut stats);
             }
 
             // get from socket
             let ServerSocket { socket, buf } = &mut server_socket;
 
             while let Ok((len, addr)) = socket.recv_from(buf) {
-                if let Some(ClientMessage {reliable, message: client_message}) = ClientMessage::decode(&buf[..len]) {
-                    received_byte_count += len;
-                    if let ClientMessageInner::Confirm(reliable) = &client_message {
-                        reliable_packages.remove(reliable);
-                    }
-                    delay_pool.push((0.0, (addr, ClientMessage {reliable, message: client_message})));
+                stats.received(len);
+                if malformed_packets.is_throttled(addr, present) || address_limiter.is_banned(addr, present) {
+                    continue;
                 }
+                incoming_link.push((addr, buf[..len].to_vec()), present);
             }
 
-            // go through delay pool
-            let mut removed = Vec::<( SocketAddr, ClientMessage )>::new();
-            delay_pool.retain_mut(|(d, sm)| {
-                *d += delta_secs;
-                if *d >= 0.1 {
-                    removed.push(sm.clone());
-                    false
+            malformed_packets.cleanup(present);
+            address_limiter.cleanup(present, &connections);
+            for (addr, datagram) in incoming_link.poll(present) {
+                // a flood is dropped before any work is done on it
+                if !address_limiter.allow_packet(addr, present) {
+                    if address_limiter.is_banned(addr, present) {
+                        remove_banned(addr, &mut connections, &incoming_sender, &thread_challenge_secret);
+                    }
+                    continue;
                 }
-                else {
-                    true
+                // garbage doesnt get a connection
+                let Some(packet) = Packet::decode(&datagram) else {
+                    malformed_packets.report(addr, present);
+                    continue;
+                };
+                // logging in fits into single datagrams, only players can make the server hold on to fragments
+                if !logged_in.contains(&addr) && matches!(packet.payload, Some(Datagram::Fragment { .. })) {
+                    continue;
+                }
+                if !connections.contains_key(&addr) && connections.len().saturating_sub(logged_in.len()) >= MAX_PENDING_CONNECTIONS {
+                    continue;
+                }
+                let connection = connections.entry(addr).or_insert_with(|| Connection::new(present));
+                // handles the acks, returns something once all fragments of a message are there
+                let Some(bytes) = connection.receive_packet(packet, present) else {
+                    continue;
+                };
+                let Some(client_message) = ClientMessage::decode(&bytes) else {
+                    malformed_packets.report(addr, present);
+                    continue;
+                };
+                if !address_limiter.allow(addr, client_message.message.class(), present) {
+                    if address_limiter.is_banned(addr, present) {
+                        remove_banned(addr, &mut connections, &incoming_sender, &thread_challenge_secret);
+                    }
+                    continue;
+                }
+                // answered right here, so the time the game needs for a tick doesnt end up in the measured round trip
+                if let ClientMessageInner::Ping(client_time) = client_message.message {
+                    let pong = ServerMessage::pong(client_time, unix_millis());
+                    for bytes in connection.send(pong.encode(), false, present).into_iter().flatten() {
+                        outgoing_link.push((addr, bytes), present);
+                    }
+                    continue;
+                }
+                let disconnect = matches!(client_message.message, ClientMessageInner::Disconnect(_));
+                // anyone can put the address of a player on a packet, only the player knows its token
+                if matches!(client_message.message, ClientMessageInner::Disconnect(token) if token != thread_challenge_secret.token(addr)) {
+                    println!("disconnect of {} with a wrong challenge token ignored", addr);
+                    continue;
+                }
+                // drops resent duplicates and holds back ordered messages until the gaps are filled
+                let ClientMessage {reliable, ordered, ..} = client_message;
+                for client_message in connection.receiver.receive(reliable, ordered, client_message) {
+                    incoming_sender.send((addr, client_message)).unwrap();
+                }
+                if disconnect {
+                    connections.remove(&addr);
                 }
-            });
-
-            for message in removed {
-                incoming_sender.send(message).unwrap();
             }
 
-            // print bytes per second
-            if sent_byte_count > 0 && delta_secs_last_sent_bytes != 0. {
-                // info!("upload per second: {}", sent_byte_count as f32 / delta_secs_last_sent_bytes / 1000000.);
-                last_sent_bytes = present;
-            }
-            if received_byte_count > 0 && delta_secs_last_received_bytes != 0. {
-                // info!("download per second: {}", received_byte_count as f32 / delta_secs_last_received_bytes / 1000000.);
-                last_received_bytes = present;
+            if let Some(net_stats) = stats.collect(present, connections.values_mut()) {
+                stats_sender.send(net_stats).unwrap();
             }
 
             std::thread::sleep(std::time::Duration::from_millis(1));
@@ -140,37 +320,53 @@ fn main() {
     App::new()
         .insert_resource(IncomingReceiver(incoming_receiver))
         .insert_resource(OutgoingSender(outgoing_sender))
+        .insert_resource(NetStatsReceiver(stats_receiver))
+        .insert_resource(NetStats::default())
         .insert_resource(Gravity(Vec3::NEG_Z * 19.))
         // .insert_resource(Gravity::ZERO)
         .insert_resource(IDCounter(0))
         .insert_resource(EntityMap::default())
         .insert_resource(NetIDMap::default())
         .insert_resource(ClientPlayerMap::default())
+        .insert_resource(challenge_secret)
+        .insert_resource(Quantization::default())
+        .insert_resource(MovementConfig::default())
+        .insert_resource(ClientSnapshots::default())
+        .insert_resource(MaxPlayers::from_env())
+        .insert_resource(AntiCheatConfig::default())
+        .add_message::<ClientDisconnected>()
+        .add_message::<ShotFired>()
         .add_plugins(DefaultPlugins)
-        .add_plugins(PhysicsPlugins::default())
-        .add_plugins(UnixTimePlugin)
+        // the whole simulation steps with the fixed tick, independent of the frame rate of the window
+        .add_plugins(PhysicsPlugins::new(FixedPostUpdate))
+        .add_plugins(TickPlugin(TickRate::from_env()))
         .add_systems(Startup, (
             setup,
             spawn_enemies,
             spawn_walls,
         ))
-        .add_systems(Update, (
-            receive_messages,
+        .add_systems(FixedUpdate, (
+            (receive_messages, apply_inputs, check_inputs, server_process_hits).chain(),
             enemy_kill_system,
-            server_process_hits,
-            broadcast_enemy_spawns,
-            broadcast_player_spawns,
+        ))
+        // after physics, so clients get the state the tick ended with
+        .add_systems(FixedLast, (
             (
-                update_per_distance_setter_increase,
+                record_hitboxes,
+                broadcast_enemy_spawns,
+                broadcast_player_spawns,
                 (
-                    broadcast_positions,
-                    broadcast_velocities,
-                    broadcast_player_looks,
-                ),
-                update_per_distance_setter_reset,
-            ).chain(),
-            broadcast_health,
-        ))
+                    update_per_distance_setter_increase,
+                    broadcast_snapshots,
+                    update_per_distance_setter_reset,
+                ).chain(),
+                broadcast_health,
+                broadcast_despawns,
+            ),
+            // after everything else so no system of this tick still works with the removed player
+            cleanup_disconnected_clients,
+        ).chain())
+        .add_systems(Update, receive_net_stats)
         .run();
 }
 
@@ -180,6 +376,12 @@ pub struct IncomingReceiver(crossbeam::channel::Receiver<(SocketAddr, ClientMess
 #[derive(Resource)]
 pub struct OutgoingSender(crossbeam::channel::Sender<(SocketAddr, ServerMessage)>);
 
+#[derive(Resource)]
+pub struct NetStatsReceiver(crossbeam::channel::Receiver<NetStats>);
+
+/// how many seconds pass between two net stats lines in the log
+const NET_STATS_LOG_INTERVAL: f32 = 5.;
+
 #[derive(Resource, Default)]
 struct NetIDMap(HashMap<Entity, NetIDType>);
 
@@ -189,9 +391,24 @@ struct EntityMap(HashMap<NetIDType, Entity>);
 #[derive(Resource, Default)]
 struct ClientPlayerMap(HashMap<SocketAddr, Entity>);
 
+/// the snapshots that were sent to each client
+#[derive(Resource, Default)]
+struct ClientSnapshots(HashMap<SocketAddr, SnapshotHistory>);
+
 #[derive(Resource)]
 struct IDCounter(pub NetIDType);
 
+/// keyed hasher with a random key per server run, turns an address into its challenge token
+/// so only someone who can receive packets at that address can log in with it, or disconnect it
+#[derive(Resource, Default, Clone)]
+struct ChallengeSecret(RandomState);
+
+impl ChallengeSecret {
+    fn token(&self, addr: SocketAddr) -> u64 {
+        self.0.hash_one(addr)
+    }
+}
+
 /// attached to entities that are being sent to players / clients
 #[derive(Component)]
 pub struct LastBroadcast(pub HashMap<SocketAddr, f32>);
@@ -199,6 +416,9 @@ pub struct LastBroadcast(pub HashMap<SocketAddr, f32>);
 #[derive(Component)]
 struct PendingSpawn;
 
+#[derive(Message)]
+struct ClientDisconnected(SocketAddr);
+
 #[derive(Component, Default)]
 struct PlayerLook(MyQuat);
 
@@ -207,12 +427,171 @@ pub struct UpdateAddress {
     addr: SocketAddr,
 }
 
-#[derive(Component)]
-struct Shooter {
-    owner: Entity,
+/// a shot out of apply_inputs, resolved by server_process_hits against the world the shooter saw
+#[derive(Message)]
+struct ShotFired {
+    shooter: Entity,
+    // the input the shot was fired in, the shooter matches the result with it
+    sequence: u32,
+    origin: Vec3,
+    direction: Dir3,
+    view_tick: TickType,
+}
+
+/// how far shots reach, across the whole map
+const SHOT_RANGE: f32 = HALF_BOUNDARY * 4.;
+/// players this close to the start or the end of a shot get told about it
+const SHOT_EVENT_RANGE: f32 = 200.;
+const SHOT_DAMAGE: f32 = 10.;
+/// the least time between two shots of one player
+const FIRE_COOLDOWN_MS: u64 = 150;
+/// a shot direction can be off by this much from unit length
+const SHOT_DIRECTION_LENGTH_TOLERANCE: f32 = 0.01;
+/// and by this much from where the player looked, in radians
+const SHOT_LOOK_TOLERANCE: f32 = 0.2;
+/// movement axes can be this much longer than 1
+const AXES_LENGTH_TOLERANCE: f32 = 0.01;
+
+/// thresholds of the input checks, every failed check adds to the suspicion of the player
+/// the movement itself is simulated by the server from the inputs, so only the inputs are checked
+#[derive(Resource, Debug, Clone, Copy)]
+struct AntiCheatConfig {
+    // sending this many more inputs than there are ticks is suspicious, 0.1 is 10%
+    input_rate_tolerance: f32,
+    // the look can turn this much from one input to the next, in radians
+    max_look_step: f32,
+    // points for inputs that couldnt come from the client, and for sending too many
+    violation_score: f32,
+    // suspicion that goes away again per second
+    decay_per_sec: f32,
+    // logged once it gets over this
+    log_score: f32,
+    // kicked once it gets over this
+    kick_score: f32,
+}
+
+impl Default for AntiCheatConfig {
+    fn default() -> Self {
+        Self {
+            input_rate_tolerance: 0.1,
+            // a quarter turn within one tick is faster than any mouse flick
+            max_look_step: std::f32::consts::FRAC_PI_2,
+            violation_score: 1.,
+            decay_per_sec: 0.5,
+            log_score: 3.,
+            kick_score: 20.,
+        }
+    }
+}
+
+/// how likely it is that the player cheats
+#[derive(Component, Default)]
+struct Suspicion {
+    score: f32,
+    // how many inputs came in beyond the one per tick the client makes
+    inputs_ahead: f32,
+}
+
+impl Suspicion {
+    fn add(&mut self, points: f32, reason: &str, player_entity: Entity, config: &AntiCheatConfig) {
+        self.score += points;
+        if self.score >= config.log_score {
+            println!("player {:?} is suspicious ({:.1}), {}", player_entity, self.score, reason);
+        }
+    }
+}
+
+/// inputs the real client cant produce, they are replaced with something harmless
+/// returns what was wrong with it
+fn sanitize_input(input: &mut InputCommand, previous_look: MyQuat, max_look_step: f32) -> Option<&'static str> {
+    let axes: Vec2 = input.axes.into();
+    if !axes.is_finite() {
+        input.axes = Vec2::ZERO.into();
+        return Some("movement axes out of range");
+    }
+    if axes.length() > 1. + AXES_LENGTH_TOLERANCE {
+        input.axes = axes.clamp_length_max(1.).into();
+        return Some("movement axes longer than 1");
+    }
+    let look: Quat = input.look.into();
+    if !look.is_finite() || !look.is_normalized() {
+        input.look = previous_look;
+        return Some("look is not a rotation");
+    }
+    // snapping onto a target, it only gets to turn as far as a fast flick
+    let previous_look: Quat = previous_look.into();
+    if previous_look.angle_between(look) > max_look_step {
+        input.look = previous_look.rotate_towards(look, max_look_step).into();
+        return Some("look turned too far in one input");
+    }
+    None
+}
+
+/// when the player last fired, for the fire cooldown
+#[derive(Component, Default)]
+struct Weapon {
+    last_fired: Option<TickType>,
+}
+
+impl Weapon {
+    /// the reason if a shot has to be rejected, None if it is fine
+    fn validate_shot(&self, direction: Vec3, look: Quat, alive: bool, tick: TickType, cooldown_ticks: TickType) -> Option<&'static str> {
+        if !alive {
+            return Some("shooter is dead");
+        }
+        if self.last_fired.is_some_and(|last_fired| tick.wrapping_sub(last_fired) < cooldown_ticks) {
+            return Some("fired faster than the cooldown");
+        }
+        if !direction.is_finite() || (direction.length() - 1.).abs() > SHOT_DIRECTION_LENGTH_TOLERANCE {
+            return Some("direction is not normalized");
+        }
+        // the look of the input the shot came with, the client puts the look of the click there
+        if (look * Vec3::Y).angle_between(direction) > SHOT_LOOK_TOLERANCE {
+            return Some("direction doesnt match the look");
+        }
+        None
+    }
 }
 
-type PlayerVelocityType = LinearVelocity;
+type PlayerVelocityType = MovementVelocity;
+
+/// a client that sends inputs faster than the tick rate doesnt get to move faster, its oldest inputs are dropped
+const MAX_QUEUED_INPUTS: usize = 16;
+
+/// inputs of a player that arrived but were not simulated yet, one is used up per tick
+#[derive(Component, Default)]
+struct InputQueue {
+    pending: VecDeque<InputCommand>,
+    newest_received: Option<u32>,
+    last_processed: Option<u32>,
+    // new inputs since the anti cheat last looked
+    received: usize,
+}
+
+impl InputQueue {
+    fn receive(&mut self, inputs: Vec<InputCommand>) {
+        for input in inputs {
+            // the same inputs are sent several times
+            if self.newest_received.is_some_and(|newest| !sequence_newer(input.sequence, newest)) {
+                continue;
+            }
+            self.newest_received = Some(input.sequence);
+            self.received += 1;
+            self.pending.push_back(input);
+        }
+        while self.pending.len() > MAX_QUEUED_INPUTS {
+            self.pending.pop_front();
+        }
+    }
+
+    /// None while no new input is there, the player then waits for it instead of guessing
+    /// so the state in the snapshots is always the one after the acked input
+    fn next(&mut self) -> Option<InputCommand> {
+        let input = self.pending.pop_front()?;
+        self.last_processed = Some(input.sequence);
+        Some(input)
+    }
+}
 
 fn receive_messages(
     incoming_receiver: Res<IncomingReceiver>,
@@ -223,24 +602,52 @@ fn receive_messages(
     mut id_counter: ResMut<IDCounter>,
     mut net_id_map: ResMut<NetIDMap>,
     mut entity_map: ResMut<EntityMap>,
-    mut player_query: Query<(&mut PlayerVelocityType, &mut Transform), With<Player>>,
-    mut player_look_query: Query<&mut PlayerLook>,
+    mut input_queue_query: Query<&mut InputQueue>,
     client_addresses: Query<Entity, With<UpdateAddress>>,
     mut client_player_map: ResMut<ClientPlayerMap>,
+    challenge_secret: Res<ChallengeSecret>,
+    mut client_disconnected: MessageWriter<ClientDisconnected>,
+    mut client_snapshots: ResMut<ClientSnapshots>,
+    (tick_rate, max_players): (Res<TickRate>, Res<MaxPlayers>),
 ) {
-    while let Ok((addr, ClientMessage {reliable, message: client_message})) = incoming_receiver.0.try_recv() {
+    while let Ok((addr, ClientMessage {message: client_message, ..})) = incoming_receiver.0.try_recv() {
+        let server_full = client_player_map.0.len() >= max_players.0;
 
-        if reliable > 0 {
-            outgoing_sender.0.send((addr, ServerMessage::confirm(reliable)));
-        }
         match client_message {
 
-            ClientMessageInner::Confirm(_) => {},
+            ClientMessageInner::Login { protocol_version } => {
+                if client_player_map.0.contains_key(&addr) {
+                    println!("duplicated login denied");
+                }
+                else if server_full {
+                    println!("login from {} denied, server is full", addr);
+                    outgoing_sender.0.send((addr, ServerMessage::rejected(RejectReason::ServerFull { max_players: max_players.0 as u32 }))).unwrap();
+                }
+                else if protocol_version != PROTOCOL_VERSION {
+                    println!("login from {} denied, protocol version {} but server has {}", addr, protocol_version, PROTOCOL_VERSION);
+                    outgoing_sender.0.send((addr, ServerMessage::rejected(RejectReason::VersionMismatch {
+                        server: PROTOCOL_VERSION,
+                        client: protocol_version,
+                    }))).unwrap();
+                }
+                else {
+                    outgoing_sender.0.send((addr, ServerMessage::challenge(challenge_secret.token(addr)))).unwrap();
+                }
+            },
 
-            ClientMessageInner::Login => {
-                if let Some(entity) = client_player_map.0.get(&addr) {
+            ClientMessageInner::ChallengeResponse(token) => {
+                if client_player_map.0.contains_key(&addr) {
                     println!("duplicated login denied");
                 }
+                // more players could have joined between the login and the response
+                else if server_full {
+                    println!("login from {} denied, server is full", addr);
+                    outgoing_sender.0.send((addr, ServerMessage::rejected(RejectReason::ServerFull { max_players: max_players.0 as u32 }))).unwrap();
+                }
+                else if token != challenge_secret.token(addr) {
+                    println!("login from {} denied, wrong challenge token", addr);
+                    outgoing_sender.0.send((addr, ServerMessage::rejected(RejectReason::BadChallenge))).unwrap();
+                }
                 else {
                     println!("login");
                     // spawn player
@@ -257,19 +664,23 @@ fn receive_messages(
                         UpdateAddress {addr},
                         PendingSpawn,
                         LastBroadcast(HashMap::new()),
+                        InputQueue::default(),
+                        HitboxHistory::default(),
+                        Weapon::default(),
+                        Suspicion::default(),
                     )).insert((
-                        LinearVelocity(Vec3::new(10., -10., 0.)),
-                        RigidBody::Dynamic,
+                        MovementVelocity::default(),
+                        // moved by apply_inputs with the same movement the client predicts with
+                        RigidBody::Kinematic,
                         CollisionLayers::new([Layer::Player], [Layer::Boundary]),
                         Collider::capsule(0.4, player_radius),
                         LockedAxes::ROTATION_LOCKED,
-                        SweptCcd::default(),
                     )).id();
 
                     client_player_map.0.insert(addr, id);
                     net_id_map.0.insert(id, id_counter.0);
                     entity_map.0.insert(id_counter.0, id);
-                    outgoing_sender.0.send((addr, ServerMessage::ok(1, id_counter.0))).unwrap();
+                    outgoing_sender.0.send((addr, ServerMessage::ok(1, id_counter.0, tick_rate.0))).unwrap();
 
                     id_counter.0 += 1;
 
@@ -280,85 +691,177 @@ fn receive_messages(
                 }
             },
 
-            ClientMessageInner::SetVelocity(player_net_id, velocity) => {
-                let player_entity_option = entity_map.0.get(&player_net_id);
-                let mut player_exists = false;
-                match player_entity_option {
-                    Some(player_entity) => {
-                        if let Ok((mut player_velocity, _)) = player_query.get_mut(*player_entity) {
-                            player_exists = true;
-                            player_velocity.0.x = velocity.x;
-                            player_velocity.0.y = velocity.y;
-                            // player_velocity.0 = Into::<Vec2>::into(velocity).extend(0.);
-                        }
-                    },
-                    None => {},
-                }
-                if !player_exists {
-                    entity_map.0.remove(&player_net_id);
-                }
-            },
+            // answered by the network thread
+            ClientMessageInner::Ping(_) => {},
 
-            ClientMessageInner::Jump(player_net_id) => {
-                let player_entity_option = entity_map.0.get(&player_net_id);
-                let mut player_exists = false;
-                match player_entity_option {
-                    Some(player_entity) => {
-                        if let Ok((mut player_velocity, _)) = player_query.get_mut(*player_entity) {
-                            player_exists = true;
-                            player_velocity.0.z = 10.;
-                        }
-                    },
-                    None => {},
-                }
-                if !player_exists {
-                    entity_map.0.remove(&player_net_id);
+            ClientMessageInner::SnapshotAck(sequence) => {
+                if let Some(history) = client_snapshots.0.get_mut(&addr) {
+                    history.acknowledge(sequence);
                 }
             },
 
-            ClientMessageInner::Rotation(player_net_id, rotation) => {
-                let player_entity_option = entity_map.0.get(&player_net_id);
-                let mut player_exists = false;
-                match player_entity_option {
-                    Some(player_entity) => {
-                        if let Ok(mut player_look) = player_look_query.get_mut(*player_entity) {
-                            player_exists = true;
-                            player_look.0 = rotation;
-                        }
-                    },
-                    None => {},
-                }
-                if !player_exists {
-                    entity_map.0.remove(&player_net_id);
-                }
+            // the token was already checked by the network thread
+            ClientMessageInner::Disconnect(_) => {
+                client_disconnected.write(ClientDisconnected(addr));
             },
 
-            ClientMessageInner::Shoot(player_net_id, direction) => {
-                let player_entity_option = entity_map.0.get(&player_net_id);
-                let mut player_exists = false;
-                match player_entity_option {
-                    Some(player_entity) => {
-                        if let Ok(( velocity, transform )) = player_query.get(*player_entity) {
-                            player_exists = true;
-                            info!("client shot");
-
-                            commands.spawn((
-                                RayCaster::new(transform.translation, Dir3::new_unchecked(direction.into())),
-                                Shooter { owner: *player_entity },
-                            ));
-                        }
-                    },
-                    None => {},
-                }
-                if !player_exists {
-                    entity_map.0.remove(&player_net_id);
-                }
+            ClientMessageInner::Input(inputs) => {
+                // the player is whoever logged in from this address, a client can only move itself
+                let Some(player_entity) = client_player_map.0.get(&addr) else {
+                    println!("protocol violation from {}, inputs without being logged in", addr);
+                    continue;
+                };
+                let Ok(mut input_queue) = input_queue_query.get_mut(*player_entity) else {
+                    println!("protocol violation from {}, its player {:?} doesnt take inputs", addr, player_entity);
+                    continue;
+                };
+                input_queue.receive(inputs);
             },
 
         }
     }
 }
 
+/// runs the movement of every player from its next input
+fn apply_inputs(
+    mut shots_fired: MessageWriter<ShotFired>,
+    outgoing_sender: Res<OutgoingSender>,
+    mut player_query: Query<(Entity, &mut InputQueue, &mut PlayerVelocityType, &mut PlayerLook, &mut Weapon, &mut Suspicion, &mut Transform, &Collider, &Health, &UpdateAddress), With<Player>>,
+    movement_config: Res<MovementConfig>,
+    anti_cheat_config: Res<AntiCheatConfig>,
+    spatial_query: SpatialQuery,
+    gravity: Res<Gravity>,
+    time: Res<Time>,
+    (tick, tick_rate): (Res<Tick>, Res<TickRate>),
+) {
+    let cooldown_ticks = tick_rate.ticks_in_millis(FIRE_COOLDOWN_MS);
+    let movement = MovementStep {
+        config: &movement_config,
+        spatial_query: &spatial_query,
+        gravity: gravity.0,
+        delta_secs: time.delta_secs(),
+    };
+
+    for (player_entity, mut input_queue, mut velocity, mut player_look, mut weapon, mut suspicion, mut transform, collider, health, addr) in &mut player_query {
+        let Some(mut input) = input_queue.next() else {
+            continue;
+        };
+        if let Some(reason) = sanitize_input(&mut input, player_look.0, anti_cheat_config.max_look_step) {
+            suspicion.add(anti_cheat_config.violation_score, reason, player_entity, &anti_cheat_config);
+        }
+        player_look.0 = input.look;
+
+        if let Some(direction) = input.fire {
+            let direction: Vec3 = direction.into();
+            if let Some(reason) = weapon.validate_shot(direction, input.look.into(), health.0 != 0., tick.0, cooldown_ticks) {
+                println!("rejected shot of {:?}, {}", player_entity, reason);
+                // the client already drew it, tell it that the shot missed
+                outgoing_sender.0.send((addr.addr, ServerMessage::shot_result(tick.0, input.sequence, None, 0., false))).unwrap();
+            }
+            else if let Ok(direction) = Dir3::new(direction) {
+                info!("client shot");
+                weapon.last_fired = Some(tick.0);
+
+                shots_fired.write(ShotFired {
+                    shooter: player_entity,
+                    sequence: input.sequence,
+                    origin: transform.translation,
+                    direction,
+                    view_tick: input.view_tick,
+                });
+            }
+        }
+
+        let state = movement.step(collider, MovementState { position: transform.translation, velocity: velocity.0 }, &input, health.0 != 0.);
+        transform.translation = state.position;
+        velocity.0 = state.velocity;
+    }
+}
+
+/// compares how many inputs every player sent with the ticks that passed, the client makes one per tick
+/// the single inputs are checked by sanitize_input, a player whose suspicion gets too high is kicked
+fn check_inputs(
+    mut player_query: Query<(Entity, &mut Suspicion, &mut InputQueue, &UpdateAddress), With<Player>>,
+    anti_cheat_config: Res<AntiCheatConfig>,
+    outgoing_sender: Res<OutgoingSender>,
+    mut client_disconnected: MessageWriter<ClientDisconnected>,
+    time: Res<Time>,
+) {
+    let delta_secs = time.delta_secs();
+    let inputs_per_tick = 1. + anti_cheat_config.input_rate_tolerance;
+
+    for (player_entity, mut suspicion, mut input_queue, addr) in &mut player_query {
+        // a late packet brings several inputs at once, the queue length is what may pile up before it counts
+        let received = std::mem::take(&mut input_queue.received) as f32;
+        suspicion.inputs_ahead = (suspicion.inputs_ahead + received - inputs_per_tick).max(0.);
+        if suspicion.inputs_ahead > MAX_QUEUED_INPUTS as f32 {
+            suspicion.inputs_ahead = MAX_QUEUED_INPUTS as f32;
+            suspicion.add(anti_cheat_config.violation_score, "sends more inputs than there are ticks", player_entity, &anti_cheat_config);
+        }
+
+        suspicion.score = (suspicion.score - anti_cheat_config.decay_per_sec * delta_secs).max(0.);
+        if suspicion.score >= anti_cheat_config.kick_score {
+            println!("kicking player {:?} at {}, suspicion {:.1}", player_entity, addr.addr, suspicion.score);
+            outgoing_sender.0.send((addr.addr, ServerMessage::kicked("suspected of cheating".to_string()))).unwrap();
+            client_disconnected.write(ClientDisconnected(addr.addr));
+            // it is removed at the end of the tick, dont kick it twice until then
+            suspicion.score = 0.;
+        }
+    }
+}
+
+/// removes everything that belonged to a client, the despawn of its player is replicated by broadcast_despawns
+fn cleanup_disconnected_clients(
+    mut client_disconnected: MessageReader<ClientDisconnected>,
+    mut commands: Commands,
+    mut client_player_map: ResMut<ClientPlayerMap>,
+    mut last_broadcasts: Query<&mut LastBroadcast>,
+    mut client_snapshots: ResMut<ClientSnapshots>,
+) {
+    for ClientDisconnected(addr) in client_disconnected.read() {
+        for mut last_broadcast in &mut last_broadcasts {
+            last_broadcast.0.remove(addr);
+        }
+        client_snapshots.0.remove(addr);
+
+        let Some(player_entity) = client_player_map.0.remove(addr) else {
+            continue;
+        };
+        println!("{} disconnected", addr);
+        commands.entity(player_entity).despawn();
+    }
+}
+
+/// networked entities that no longer exist are removed from the maps and despawned on every client
+fn broadcast_despawns(
+    outgoing_sender: Res<OutgoingSender>,
+    entities: &Entities,
+    mut net_id_map: ResMut<NetIDMap>,
+    mut entity_map: ResMut<EntityMap>,
+    client_addresses: Query<&UpdateAddress>,
+    tick: Res<Tick>,
+) {
+    let mut despawned = Vec::<NetIDType>::new();
+    net_id_map.0.retain(|entity, net_id| {
+        let alive = entities.contains(*entity);
+        if !alive {
+            despawned.push(*net_id);
+        }
+        alive
+    });
+
+    if despawned.is_empty() {
+        return;
+    }
+    for net_id in &despawned {
+        entity_map.0.remove(net_id);
+    }
+
+    for client in &client_addresses {
+        outgoing_sender.0.send((client.addr, ServerMessage::despawn_entities(tick.0, despawned.clone()))).unwrap();
+    }
+}
+
 fn broadcast_player_spawns(
     outgoing_sender: Res<OutgoingSender>,
     mut commands: Commands,
@@ -366,6 +869,7 @@ fn broadcast_player_spawns(
     net_id_map: ResMut<NetIDMap>,
     client_addresses: Query<(Entity, &UpdateAddress), With<PendingSpawn>>,
     player_query: Query<(Entity, &Transform, &PlayerVelocityType, &MeshMaterial3d<StandardMaterial>, &Player, &Health, &Radius)>,
+    tick: Res<Tick>,
 ) {
     for (id, addr) in client_addresses.iter() {
         // println!("client spawn");
@@ -384,9 +888,8 @@ fn broadcast_player_spawns(
                 NetComponent::SpotLight(radius.0),
             ] });
         }
-        for chonky in entity_packages.chunks(2) {
-            outgoing_sender.0.send((addr.addr, ServerMessage::spawn_entities(1, chonky.to_vec()))).unwrap();
-        }
+        // big spawn messages get fragmented by the network thread
+        outgoing_sender.0.send((addr.addr, ServerMessage::spawn_entities(1, tick.0, entity_packages))).unwrap();
         println!("sending player spawn");
         commands.entity(id).remove::<PendingSpawn>();
     }
@@ -398,6 +901,7 @@ fn broadcast_enemy_spawns(
     net_id_map: ResMut<NetIDMap>,
     client_addresses: Query<(Entity, &UpdateAddress), With<PendingSpawn>>,
     enemy_query: Query<(Entity, &Transform, &LinearVelocity, &MeshMaterial3d<StandardMaterial>, &Enemy, &Radius)>,
+    tick: Res<Tick>,
 ) {
     for (_, addr) in client_addresses.iter() {
         let mut entity_packages = Vec::<EntityPackage>::new();
@@ -414,18 +918,10 @@ fn broadcast_enemy_spawns(
                 (*radius).into(),
             ] });
         }
-        for chonky in entity_packages.chunks(5) {
-            outgoing_sender.0.send((addr.addr, ServerMessage::spawn_entities(1, chonky.to_vec()))).unwrap();
-            // commands.entity(id).remove::<PendingSpawn>();
-        }
+        outgoing_sender.0.send((addr.addr, ServerMessage::spawn_entities(1, tick.0, entity_packages))).unwrap();
     }
 }
 
-const POSITION_PACKAGES_PER_MESSAGE: usize = (1000. / std::mem::size_of::<PositionPackage>() as f32).floor() as usize;
-const VELOCITY_PACKAGES_PER_MESSAGE: usize = (1000. / std::mem::size_of::<VelocityPackage>() as f32).floor() as usize;
-const PLAYER_LOOK_PACKAGES_PER_MESSAGE: usize = (1000. / std::mem::size_of::<PlayerLookPackage>() as f32).floor() as usize;
-const HEALTH_PACKAGES_PER_MESSAGE: usize = (1000. / std::mem::size_of::<HealthPackage>() as f32).floor() as usize;
-
 fn update_per_distance_check(lb: f32, distance: f32) -> bool {
    lb >= distance / 500. + 0.01
 }
@@ -521,6 +1017,7 @@ fn broadcast_health(
     mut query: Query<(Entity, &Health), Changed<Health>>,
     net_id_map: ResMut<NetIDMap>,
     time: Res<Time>,
+    tick: Res<Tick>,
 ) {
     let delta_secs = time.delta_secs();
 
@@ -542,130 +1039,71 @@ fn broadcast_health(
             .collect();
 
         // Split into chunks and send
-        for chunk in nearby_entities.chunks(HEALTH_PACKAGES_PER_MESSAGE) {
-            let message = ServerMessage::update_healths(chunk.to_vec());
+        for chunk in chunk_packages(&nearby_entities) {
+            let message = ServerMessage::update_healths(tick.0, chunk);
             outgoing_sender.0.send((addr.addr, message)).unwrap();
         }
     }
 }
 
-fn broadcast_player_looks(
+/// builds what every client sees this frame and sends it compressed against the last snapshot the client acked
+/// entities that update_per_distance holds back keep the value they were last sent with
+/// the own player is never held back, its state has to belong to the input_ack that is sent with it
+fn broadcast_snapshots(
     outgoing_sender: Res<OutgoingSender>,
-    client_addresses: Query<(Entity, &UpdateAddress, &Transform)>,
-    mut query: Query<(Entity, &Transform, &PlayerLook, Option<&LastBroadcast>)>,
-    net_id_map: ResMut<NetIDMap>,
+    client_addresses: Query<(Entity, &UpdateAddress, &Transform, Option<&InputQueue>)>,
+    query: Query<(Entity, &Transform, &LinearVelocity, Option<&MovementVelocity>, Option<&PlayerLook>, &LastBroadcast)>,
+    net_id_map: Res<NetIDMap>,
     time: Res<Time>,
+    tick: Res<Tick>,
+    quantization: Res<Quantization>,
+    mut client_snapshots: ResMut<ClientSnapshots>,
 ) {
     let delta_secs = time.delta_secs();
 
     // Process each client separately
-    for (_entity, addr, player_transform) in client_addresses.iter() {
+    for (client, addr, player_transform, input_queue) in client_addresses.iter() {
         let player_pos = player_transform.translation;
+        let history = client_snapshots.0.entry(addr.addr).or_default();
 
-        // Collect enemies within radius for this specific player
-        let nearby_entities: Vec<PlayerLookPackage> = query
-            .iter_mut()
-            .filter_map(|(entity, entity_transform, player_look, last_broadcast_option)| {
-                let distance = player_pos.distance(entity_transform.translation);
-                let net_id = net_id_map.0.get(&entity)?;
-
-                if update_per_distance(addr.addr, delta_secs, last_broadcast_option, distance) {
-                    Some(PlayerLookPackage {
-                        net_id: *net_id,
-                        rotation: player_look.0,
-                    })
-                }
-                else {
-                    None
-                }
-            })
-            .collect();
-
-        // Split into chunks and send
-        for chunk in nearby_entities.chunks(PLAYER_LOOK_PACKAGES_PER_MESSAGE) {
-            let message = ServerMessage::update_player_looks(chunk.to_vec());
-            outgoing_sender.0.send((addr.addr, message)).unwrap();
+        let mut snapshot = Snapshot::default();
+        for (entity, entity_transform, entity_velocity, movement_velocity, player_look, last_broadcast) in query.iter() {
+            let Some(net_id) = net_id_map.0.get(&entity) else {
+                continue;
+            };
+            let distance = player_pos.distance(entity_transform.translation);
+            let last_sent = history.latest().and_then(|(_, latest)| latest.entities.get(net_id));
+
+            let state = match last_sent {
+                Some(last_sent) if entity != client && !update_per_distance(addr.addr, delta_secs, Some(last_broadcast), distance) => *last_sent,
+                _ => EntityState {
+                    position: NetVec3::new(entity_transform.translation, quantization.positions),
+                    rotation: NetQuat::new(entity_transform.rotation, quantization.positions),
+                    // players are kinematic, their linear velocity stays zero
+                    velocity: movement_velocity.map_or(entity_velocity.0, |velocity| velocity.0).into(),
+                    look: player_look.map(|look| NetQuat::new(look.0.into(), quantization.looks)),
+                },
+            };
+            snapshot.entities.insert(*net_id, state);
         }
-    }
-}
-
-fn broadcast_positions(
-    outgoing_sender: Res<OutgoingSender>,
-    client_addresses: Query<(Entity, &UpdateAddress, &Transform)>,
-    mut query: Query<(Entity, &Transform, Option<&LastBroadcast>)>,
-    net_id_map: ResMut<NetIDMap>,
-    time: Res<Time>,
-    unix_time: Res<UnixTime>,
-) {
-    let delta_secs = time.delta_secs();
 
-    // Process each client separately
-    for (_entity, addr, player_transform) in client_addresses.iter() {
-        let player_pos = player_transform.translation;
-
-        // Collect enemies within radius for this specific player
-        let nearby_entities: Vec<PositionPackage> = query
-            .iter_mut()
-            .filter_map(|(entity, entity_transform, last_broadcast_option)| {
-                let distance = player_pos.distance(entity_transform.translation);
-                let net_id = net_id_map.0.get(&entity)?;
-
-                if update_per_distance(addr.addr, delta_secs, last_broadcast_option, distance) {
-                    Some(PositionPackage {
-                        net_id: *net_id,
-                        position: entity_transform.translation.into(),
-                        rotation: entity_transform.rotation.into(),
-                    })
-                }
-                else {
-                    None
-                }
-            })
-            .collect();
+        let baseline = history.baseline();
+        let entities = snapshot.delta(baseline.map(|(_, baseline)| baseline));
+        let baseline_sequence = baseline.map(|(sequence, _)| sequence);
+        let sequence = history.push(snapshot);
+        let input_ack = input_queue.and_then(|input_queue| input_queue.last_processed);
 
-        // Split into chunks and send
-        for chunk in nearby_entities.chunks(POSITION_PACKAGES_PER_MESSAGE) {
-            let message = ServerMessage::update_positions(unix_time.0, chunk.to_vec());
-            outgoing_sender.0.send((addr.addr, message)).unwrap();
+        let mut chunks = chunk_packages(&entities);
+        // an empty snapshot still acks the input
+        if chunks.is_empty() {
+            chunks.push(Vec::new());
         }
-    }
-}
-
-fn broadcast_velocities(
-    outgoing_sender: Res<OutgoingSender>,
-    client_addresses: Query<(Entity, &UpdateAddress, &Transform)>,
-    mut query: Query<(Entity, &Transform, &LinearVelocity, Option<&LastBroadcast>)>,
-    net_id_map: ResMut<NetIDMap>,
-    time: Res<Time>,
-) {
-    let delta_secs = time.delta_secs();
-
-    // Process each client separately
-    for (_entity, addr, player_transform) in client_addresses.iter() {
-        let player_pos = player_transform.translation;
-
-        // Collect enemies within radius for this specific player
-        let nearby_entities: Vec<VelocityPackage> = query
-            .iter_mut()
-            .filter_map(|(entity, entity_transform, entity_velocity, last_broadcast_option)| {
-                let distance = player_pos.distance(entity_transform.translation);
-                let net_id = net_id_map.0.get(&entity)?;
-
-                if update_per_distance(addr.addr, delta_secs, last_broadcast_option, distance) {
-                    Some(VelocityPackage {
-                        net_id: *net_id,
-                        velocity: entity_velocity.0.into(),
-                    })
-                }
-                else {
-                    None
-                }
-            })
-            .collect();
-
-        // Split into chunks and send
-        for chunk in nearby_entities.chunks(VELOCITY_PACKAGES_PER_MESSAGE) {
-            let message = ServerMessage::update_velocities(chunk.to_vec());
+        let Ok(parts) = u8::try_from(chunks.len()) else {
+            println!("snapshot for {} too big to be sent, dropping it", addr.addr);
+            continue;
+        };
+        for (part, chunk) in chunks.into_iter().enumerate() {
+            let message = ServerMessage::snapshot(sequence, baseline_sequence, tick.0, input_ack, part as u8, parts, chunk);
             outgoing_sender.0.send((addr.addr, message)).unwrap();
         }
     }
@@ -790,6 +1228,7 @@ fn spawn_enemies(
             Enemy,
             Radius(enemy_radius),
             LastBroadcast(HashMap::new()),
+            HitboxHistory::default(),
         )).id();
 
         net_id_map.0.insert(id, id_counter.0);
@@ -812,36 +1251,119 @@ fn enemy_kill_system(
     }
 }
 
+/// checks shots against the hitboxes as they were at the shooters view tick, at most MAX_REWIND_MS back
+/// the map doesnt move, so it is checked as it is now
+/// the shooter gets the result, everyone close by gets the shot to draw it
 fn server_process_hits(
-    mut commands: Commands,
-    query: Query<(Entity, &RayCaster, &RayHits, &Shooter)>,
+    mut shots_fired: MessageReader<ShotFired>,
+    hitbox_query: Query<(Entity, &HitboxHistory, &Collider)>,
     mut health_q: Query<&mut Health>,
-    mut velocity_q: Query<&mut Velocity>,
+    client_query: Query<(Entity, &UpdateAddress, &Transform)>,
+    spatial_query: SpatialQuery,
+    outgoing_sender: Res<OutgoingSender>,
+    net_id_map: Res<NetIDMap>,
+    (tick, tick_rate): (Res<Tick>, Res<TickRate>),
 ) {
-    for (ray_entity, ray, hits, shooter) in &query {
-        // Find first hit that is NOT the shooter
-        let valid_hit = hits
-            .iter_sorted()
-            .find(|hit| hit.entity != shooter.owner);
+    let oldest_tick = tick.0.wrapping_sub(tick_rate.ticks_in_millis(MAX_REWIND_MS));
+
+    for shot in shots_fired.read() {
+        let view_tick = if sequence_newer(shot.view_tick, tick.0) {
+            tick.0
+        }
+        else if sequence_newer(oldest_tick, shot.view_tick) {
+            oldest_tick
+        }
+        else {
+            shot.view_tick
+        };
+
+        let max_distance = spatial_query
+            .cast_ray(shot.origin, shot.direction, SHOT_RANGE, true, &SpatialQueryFilter::from_mask(Layer::Boundary))
+            .map_or(SHOT_RANGE, |hit| hit.distance);
 
-        if let Some(hit) = valid_hit {
-            let hit_entity = hit.entity;
+        // Find first hit that is NOT the shooter
+        let valid_hit = hitbox_query
+            .iter()
+            .filter(|(entity, _, _)| *entity != shot.shooter)
+            .filter_map(|(entity, history, collider)| {
+                let (position, rotation) = history.at(view_tick)?;
+                let (distance, _normal) = collider.cast_ray(position, rotation, shot.origin, shot.direction.into(), max_distance, true)?;
+                Some((entity, distance))
+            })
+            .min_by(|(_, a), (_, b)| a.total_cmp(b));
 
+        let mut damage = 0.;
+        let mut kill = false;
+        if let Some((hit_entity, distance)) = valid_hit {
             // Damage
             if let Ok(mut health) = health_q.get_mut(hit_entity) {
-                health.0 -= 10.;
+                damage = SHOT_DAMAGE.min(health.0);
+                health.0 -= damage;
+                kill = damage > 0. && health.0 <= 0.;
                 if health.0 < 0. {
                     health.0 = 0.;
                 }
             }
 
             info!(
-                "Shooter {:?} hit {:?} at {}",
-                shooter.owner, hit_entity, hit.distance
+                "Shooter {:?} hit {:?} at {} rewound {} ticks",
+                shot.shooter, hit_entity, distance, tick.0.wrapping_sub(view_tick)
             );
         }
 
-        // One-frame ray
-        commands.entity(ray_entity).despawn();
+        let hit_net_id = valid_hit.and_then(|(hit_entity, _)| net_id_map.0.get(&hit_entity).copied());
+        let end = shot.origin + shot.direction * valid_hit.map_or(max_distance, |(_, distance)| distance);
+        let Some(shooter_net_id) = net_id_map.0.get(&shot.shooter) else {
+            continue;
+        };
+
+        for (entity, addr, transform) in &client_query {
+            if entity == shot.shooter {
+                outgoing_sender.0.send((addr.addr, ServerMessage::shot_result(tick.0, shot.sequence, hit_net_id, damage, kill))).unwrap();
+            }
+            else if transform.translation.distance(shot.origin) <= SHOT_EVENT_RANGE || transform.translation.distance(end) <= SHOT_EVENT_RANGE {
+                outgoing_sender.0.send((addr.addr, ServerMessage::shot(tick.0, *shooter_net_id, shot.origin, end, hit_net_id))).unwrap();
+            }
+        }
+    }
+}
+
+/// remembers where every hitbox ended up this tick, for server_process_hits
+fn record_hitboxes(
+    mut hitbox_query: Query<(&mut HitboxHistory, &Transform)>,
+    tick: Res<Tick>,
+) {
+    for (mut history, transform) in &mut hitbox_query {
+        history.push(tick.0, transform.translation, transform.rotation);
+    }
+}
+
+fn receive_net_stats(
+    stats_receiver: Res<NetStatsReceiver>,
+    mut net_stats: ResMut<NetStats>,
+    time: Res<Time>,
+    mut since_log: Local<f32>,
+) {
+    while let Ok(stats) = stats_receiver.0.try_recv() {
+        *net_stats = stats;
+    }
+
+    *since_log += time.delta_secs();
+    if *since_log < NET_STATS_LOG_INTERVAL {
+        return;
     }
+    *since_log = 0.;
+
+    info!(
+        "{} clients, up {:.1} kB/s ({:.0} packets/s), down {:.1} kB/s ({:.0} packets/s), {:.1} resends/s, {} pending reliable, rtt {:.0} ms, loss {:.1}%",
+        net_stats.connections,
+        net_stats.bytes_up_per_sec / 1000.,
+        net_stats.packets_up_per_sec,
+        net_stats.bytes_down_per_sec / 1000.,
+        net_stats.packets_down_per_sec,
+        net_stats.resends_per_sec,
+        net_stats.pending_reliable,
+        net_stats.rtt_ms,
+        net_stats.loss * 100.,
+    );
 }
@@ -0,0 +1,141 @@
+use rand::Rng;
+use std::time::{Duration, Instant};
+use crate::*;
+
+/// environment variable that selects the link profile at startup
+pub const NET_PROFILE_ENV: &str = "NET_PROFILE";
+
+/// simulated network conditions for one direction of a link
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LinkProfile {
+    pub latency: Duration,
+    // random extra delay between 0 and this
+    pub jitter: Duration,
+    // chances between 0 and 1
+    pub loss: f32,
+    pub duplicate: f32,
+    pub reorder: f32,
+}
+
+impl Default for LinkProfile {
+    fn default() -> Self {
+        Self::OFF
+    }
+}
+
+impl LinkProfile {
+    pub const OFF: Self = Self {
+        latency: Duration::ZERO,
+        jitter: Duration::ZERO,
+        loss: 0.,
+        duplicate: 0.,
+        reorder: 0.,
+    };
+    pub const LAN: Self = Self {
+        latency: Duration::from_millis(2),
+        jitter: Duration::from_millis(1),
+        ..Self::OFF
+    };
+    pub const WIFI: Self = Self {
+        latency: Duration::from_millis(20),
+        jitter: Duration::from_millis(10),
+        loss: 0.01,
+        ..Self::OFF
+    };
+    // what the old hard coded delay pool did
+    pub const SLOW: Self = Self {
+        latency: Duration::from_millis(100),
+        ..Self::OFF
+    };
+    pub const MOBILE: Self = Self {
+        latency: Duration::from_millis(60),
+        jitter: Duration::from_millis(30),
+        loss: 0.03,
+        duplicate: 0.01,
+        reorder: 0.02,
+    };
+    pub const TERRIBLE: Self = Self {
+        latency: Duration::from_millis(150),
+        jitter: Duration::from_millis(75),
+        loss: 0.1,
+        duplicate: 0.05,
+        reorder: 0.1,
+    };
+
+    pub const NAMES: [&str; 6] = ["off", "lan", "wifi", "slow", "mobile", "terrible"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "off" => Some(Self::OFF),
+            "lan" => Some(Self::LAN),
+            "wifi" => Some(Self::WIFI),
+            "slow" => Some(Self::SLOW),
+            "mobile" => Some(Self::MOBILE),
+            "terrible" => Some(Self::TERRIBLE),
+            _ => None,
+        }
+    }
+
+    /// reads the profile name from NET_PROFILE, one of NAMES, off if it is not set
+    pub fn from_env() -> Self {
+        env_or_default(NET_PROFILE_ENV, Self::OFF, Self::from_name)
+    }
+
+    pub fn is_off(&self) -> bool {
+        *self == Self::OFF
+    }
+}
+
+/// holds back datagrams to simulate a bad network, drops, duplicates and reorders them
+pub struct LinkConditioner<T> {
+    pub profile: LinkProfile,
+    queue: Vec<(Instant, T)>,
+    // packets that are not reordered dont leave before this
+    last_release: Instant,
+}
+
+impl<T: Clone> LinkConditioner<T> {
+    pub fn new(profile: LinkProfile) -> Self {
+        Self {
+            profile,
+            queue: Vec::new(),
+            last_release: Instant::now(),
+        }
+    }
+
+    pub fn push(&mut self, item: T, now: Instant) {
+        if self.profile.is_off() {
+            self.queue.push((now, item));
+            return;
+        }
+
+        let mut rng = rand::rng();
+        if rng.random::<f32>() < self.profile.loss {
+            return;
+        }
+
+        let copies = if rng.random::<f32>() < self.profile.duplicate { 2 } else { 1 };
+        for _ in 0..copies {
+            let jitter = self.profile.jitter.mul_f32(rng.random::<f32>());
+            let mut release = now + self.profile.latency + jitter;
+
+            if rng.random::<f32>() < self.profile.reorder {
+                // held back a bit longer so later packets overtake it
+                release += self.profile.latency.max(Duration::from_millis(10)).mul_f32(rng.random_range(0.5..1.5));
+            }
+            else {
+                release = release.max(self.last_release);
+                self.last_release = release;
+            }
+
+            self.queue.push((release, item.clone()));
+        }
+    }
+
+    /// everything that is due now, in the order it should arrive
+    pub fn poll(&mut self, now: Instant) -> Vec<T> {
+        self.queue.sort_by_key(|(release, _)| *release);
+        let due = self.queue.partition_point(|(release, _)| *release <= now);
+        self.queue.drain(..due).map(|(_, item)| item).collect()
+    }
+}
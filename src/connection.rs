@@ -0,0 +1,425 @@
+use bincode::{Decode, Encode};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::time::{Duration, Instant};
+use crate::fragment::*;
+use crate::decode::*;
+
+/// unconfirmed reliable messages are sent again after this time
+pub const RESEND_INTERVAL: Duration = Duration::from_millis(300);
+/// if nothing else was sent for this long, a packet with only acks goes out
+pub const ACK_INTERVAL: Duration = Duration::from_millis(50);
+/// how many reliable ids above a gap are remembered, or ordered messages are held back
+pub const RECEIVE_WINDOW: usize = 1024;
+/// a peer that didnt send anything for this long is considered gone
+pub const CONNECTION_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// put in front of every datagram, acks the newest received packet and the 32 before it
+#[derive(Encode, Decode, Debug, Clone, Copy)]
+pub struct PacketHeader {
+    pub sequence: u16,
+    pub ack: u16,
+    pub ack_bits: u32,
+}
+
+#[derive(Encode, Decode, Debug, Clone)]
+pub struct Packet {
+    pub header: PacketHeader,
+    // None means the packet only carries acks
+    pub payload: Option<Datagram>,
+}
+
+impl Packet {
+    pub fn encode(&self) -> Vec<u8> {
+        bincode::encode_to_vec(self, bincode::config::standard()).unwrap()
+    }
+    pub fn decode(slice: &[u8]) -> Option<Self> {
+        decode_exact::<Self, MAX_DATAGRAM_SIZE>(slice)
+            .filter(|packet| packet.payload.as_ref().is_none_or(Datagram::is_valid))
+    }
+}
+
+/// true if s1 is newer than s2, handles wrap around
+pub fn sequence_greater_than(s1: u16, s2: u16) -> bool {
+    ((s1 > s2) && (s1 - s2 <= 32768)) || ((s1 < s2) && (s2 - s1 > 32768))
+}
+
+/// drops reliable messages that were already delivered and holds back ordered ones until the gaps are filled
+pub struct ReliableReceiver<T> {
+    // every unordered id up to this one was delivered
+    unordered_contiguous: usize,
+    unordered_above: BTreeSet<usize>,
+    next_ordered: usize,
+    held_back: BTreeMap<usize, T>,
+}
+
+impl<T> Default for ReliableReceiver<T> {
+    fn default() -> Self {
+        Self {
+            unordered_contiguous: 0,
+            unordered_above: BTreeSet::new(),
+            next_ordered: 1,
+            held_back: BTreeMap::new(),
+        }
+    }
+}
+
+impl<T> ReliableReceiver<T> {
+    /// returns the messages that can be handed to the game now, in order
+    pub fn receive(&mut self, reliable: usize, ordered: bool, message: T) -> Vec<T> {
+        if reliable == 0 {
+            return vec![message];
+        }
+
+        if ordered {
+            if reliable < self.next_ordered || self.held_back.contains_key(&reliable) {
+                return Vec::new();
+            }
+            // the peer picks the ids, so nothing here may overflow
+            if self.next_ordered.checked_add(RECEIVE_WINDOW).is_none_or(|end| reliable >= end) {
+                // way ahead of what we are waiting for, it will be resent
+                return Vec::new();
+            }
+            self.held_back.insert(reliable, message);
+
+            let mut ready = Vec::new();
+            while let Some(message) = self.held_back.remove(&self.next_ordered) {
+                ready.push(message);
+                let Some(next) = self.next_ordered.checked_add(1) else {
+                    break;
+                };
+                self.next_ordered = next;
+            }
+            ready
+        }
+        else {
+            if reliable <= self.unordered_contiguous || self.unordered_above.contains(&reliable) {
+                return Vec::new();
+            }
+            // same as the ordered ones, it is resent once the gap below it is filled
+            if self.unordered_contiguous.checked_add(RECEIVE_WINDOW).is_none_or(|end| reliable > end) {
+                return Vec::new();
+            }
+            self.unordered_above.insert(reliable);
+            while let Some(next) = self.unordered_contiguous.checked_add(1) {
+                if !self.unordered_above.remove(&next) {
+                    break;
+                }
+                self.unordered_contiguous = next;
+            }
+            vec![message]
+        }
+    }
+}
+
+struct PendingReliable {
+    datagrams: Vec<Datagram>,
+    acked: Vec<bool>,
+    last_send: Instant,
+    // when all fragments were last sent, the peer drops a group that isnt complete after FRAGMENT_TIMEOUT
+    group_send: Instant,
+}
+
+/// state of one peer, sequence numbers and reliable messages are counted per connection
+/// T is the type of the messages that come in from that peer
+pub struct Connection<T> {
+    local_sequence: u16,
+    remote_sequence: u16,
+    // bit i set means packet remote_sequence - 1 - i was received
+    received_bits: u32,
+    received_any: bool,
+    // something was received that wasnt acked yet
+    ack_pending: bool,
+
+    next_unordered_id: usize,
+    next_ordered_id: usize,
+    next_pending_id: usize,
+    pending_reliable: HashMap<usize, PendingReliable>,
+    // packet sequence -> (pending id, fragment index)
+    in_flight: HashMap<u16, (usize, usize)>,
+    pub receiver: ReliableReceiver<T>,
+
+    fragmenter: Fragmenter,
+    reassembler: Reassembler,
+
+    pub last_send: Instant,
+    pub last_receive: Instant,
+
+    // for rtt and loss, every packet that could still be acked
+    sent_times: HashMap<u16, Instant>,
+    // smoothed from the acks
+    pub rtt_ms: f32,
+    // smoothed share of packets that never got acked, between 0 and 1
+    pub loss: f32,
+    // counted up until taken by the stats
+    resends: usize,
+}
+
+impl<T> Connection<T> {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            // the peer acks 0 before it received anything, so that sequence is only used after the first wrap around
+            local_sequence: 1,
+            remote_sequence: 0,
+            received_bits: 0,
+            received_any: false,
+            ack_pending: false,
+            next_unordered_id: 1,
+            next_ordered_id: 1,
+            next_pending_id: 0,
+            pending_reliable: HashMap::new(),
+            in_flight: HashMap::new(),
+            receiver: ReliableReceiver::default(),
+            fragmenter: Fragmenter::default(),
+            reassembler: Reassembler::default(),
+            last_send: now,
+            last_receive: now,
+            sent_times: HashMap::new(),
+            rtt_ms: 0.,
+            loss: 0.,
+            resends: 0,
+        }
+    }
+
+    /// 0 is reserved for unreliable messages, ordered and unordered messages are counted separately
+    pub fn next_reliable_id(&mut self, ordered: bool) -> usize {
+        let counter = if ordered { &mut self.next_ordered_id } else { &mut self.next_unordered_id };
+        let id = *counter;
+        *counter += 1;
+        id
+    }
+
+    pub fn pending_reliable_count(&self) -> usize {
+        self.pending_reliable.len()
+    }
+
+    /// resent fragments since the last call
+    pub fn take_resends(&mut self) -> usize {
+        std::mem::take(&mut self.resends)
+    }
+
+    fn header(&mut self) -> PacketHeader {
+        let header = PacketHeader {
+            sequence: self.local_sequence,
+            ack: self.remote_sequence,
+            ack_bits: self.received_bits,
+        };
+        self.local_sequence = self.local_sequence.wrapping_add(1);
+        header
+    }
+
+    fn packet(&mut self, payload: Option<Datagram>, now: Instant) -> (u16, Vec<u8>) {
+        let header = self.header();
+        self.ack_pending = false;
+        self.last_send = now;
+        self.sent_times.insert(header.sequence, now);
+        (header.sequence, Packet { header, payload }.encode())
+    }
+
+    /// turns an encoded message into datagrams, reliable ones are kept around until they are acked
+    pub fn send(&mut self, bytes: Vec<u8>, reliable: bool, now: Instant) -> Option<Vec<Vec<u8>>> {
+        let datagrams = self.fragmenter.split(bytes)?;
+        let pending_id = self.next_pending_id;
+
+        let mut out = Vec::with_capacity(datagrams.len());
+        for (index, datagram) in datagrams.iter().enumerate() {
+            let (sequence, bytes) = self.packet(Some(datagram.clone()), now);
+            if reliable {
+                self.in_flight.insert(sequence, (pending_id, index));
+            }
+            out.push(bytes);
+        }
+
+        if reliable {
+            self.next_pending_id += 1;
+            self.pending_reliable.insert(pending_id, PendingReliable {
+                acked: vec![false; datagrams.len()],
+                datagrams,
+                last_send: now,
+                group_send: now,
+            });
+        }
+        Some(out)
+    }
+
+    /// resends unacked reliable fragments and keeps the acks flowing, returns what has to be put on the wire
+    pub fn update(&mut self, now: Instant) -> Vec<Vec<u8>> {
+        let mut out = Vec::new();
+
+        let mut resends = Vec::new();
+        let mut restarted = Vec::new();
+        for (id, pending) in self.pending_reliable.iter_mut() {
+            // the acked fragments may be gone with the incomplete group on the other side, send all of them again
+            let restart = pending.datagrams.len() > 1 && now.duration_since(pending.group_send) >= FRAGMENT_TIMEOUT;
+            if restart {
+                pending.acked.fill(false);
+                pending.group_send = now;
+                restarted.push(*id);
+            }
+            if restart || now.duration_since(pending.last_send) > RESEND_INTERVAL {
+                pending.last_send = now;
+                for (index, datagram) in pending.datagrams.iter().enumerate() {
+                    if !pending.acked[index] {
+                        resends.push((*id, index, datagram.clone()));
+                    }
+                }
+            }
+        }
+        // acks of the fragments sent before dont say anything about the new group
+        self.in_flight.retain(|_, (id, _)| !restarted.contains(id));
+        for (id, index, datagram) in resends {
+            let (sequence, bytes) = self.packet(Some(datagram), now);
+            self.in_flight.insert(sequence, (id, index));
+            self.resends += 1;
+            out.push(bytes);
+        }
+
+        // packets this old can no longer be acked by the bitfield
+        let local_sequence = self.local_sequence;
+        self.in_flight.retain(|sequence, _| local_sequence.wrapping_sub(*sequence) <= 64);
+
+        let mut lost = 0;
+        self.sent_times.retain(|sequence, _| {
+            let ackable = local_sequence.wrapping_sub(*sequence) <= 64;
+            if !ackable {
+                lost += 1;
+            }
+            ackable
+        });
+        for _ in 0..lost {
+            self.loss = self.loss * 0.99 + 0.01;
+        }
+
+        if self.ack_pending && now.duration_since(self.last_send) > ACK_INTERVAL {
+            let (_, bytes) = self.packet(None, now);
+            out.push(bytes);
+        }
+
+        out
+    }
+
+    fn acknowledge(&mut self, sequence: u16, now: Instant) {
+        if let Some(sent) = self.sent_times.remove(&sequence) {
+            let rtt_ms = now.duration_since(sent).as_secs_f32() * 1000.;
+            self.rtt_ms = if self.rtt_ms == 0. { rtt_ms } else { self.rtt_ms * 0.9 + rtt_ms * 0.1 };
+            self.loss *= 0.99;
+        }
+        if let Some((id, index)) = self.in_flight.remove(&sequence) {
+            if let Some(pending) = self.pending_reliable.get_mut(&id) {
+                pending.acked[index] = true;
+                if pending.acked.iter().all(|acked| *acked) {
+                    self.pending_reliable.remove(&id);
+                }
+            }
+        }
+    }
+
+    /// returns false if the packet was already received
+    fn mark_received(&mut self, sequence: u16) -> bool {
+        if !self.received_any {
+            self.received_any = true;
+            self.remote_sequence = sequence;
+            self.received_bits = 0;
+            return true;
+        }
+
+        if sequence_greater_than(sequence, self.remote_sequence) {
+            let shift = sequence.wrapping_sub(self.remote_sequence) as u32;
+            self.received_bits = self.received_bits.checked_shl(shift).unwrap_or(0)
+                | 1u32.checked_shl(shift - 1).unwrap_or(0);
+            self.remote_sequence = sequence;
+            true
+        }
+        else {
+            let distance = self.remote_sequence.wrapping_sub(sequence) as u32;
+            if distance == 0 || distance > 32 {
+                // duplicate or too old to tell
+                return false;
+            }
+            let bit = 1u32 << (distance - 1);
+            let new = self.received_bits & bit == 0;
+            self.received_bits |= bit;
+            new
+        }
+    }
+
+    /// processes the acks of a datagram, returns the message bytes once a whole message arrived
+    pub fn receive(&mut self, slice: &[u8], now: Instant) -> Option<Vec<u8>> {
+        self.receive_packet(Packet::decode(slice)?, now)
+    }
+
+    /// like receive, for a packet that was already decoded
+    pub fn receive_packet(&mut self, packet: Packet, now: Instant) -> Option<Vec<u8>> {
+        let Packet { header, payload } = packet;
+        self.last_receive = now;
+
+        self.acknowledge(header.ack, now);
+        for i in 0..32u16 {
+            if header.ack_bits & (1 << i) != 0 {
+                self.acknowledge(header.ack.wrapping_sub(i + 1), now);
+            }
+        }
+
+        // a rejected fragment isnt acked, so the peer sends it again
+        if payload.as_ref().is_some_and(|datagram| !self.reassembler.accepts(datagram, now)) {
+            return None;
+        }
+
+        if !self.mark_received(header.sequence) {
+            return None;
+        }
+        self.ack_pending = true;
+
+        self.reassembler.receive(payload?, now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn reliable_group_survives_outage() {
+        let start = Instant::now();
+        let mut sender = Connection::<()>::new(start);
+        let mut receiver = Connection::<()>::new(start);
+
+        let message: Vec<u8> = (0..MAX_PAYLOAD_SIZE * 2 + 10).map(|i| i as u8).collect();
+        let packets = sender.send(message.clone(), true, start).unwrap();
+        assert_eq!(packets.len(), 3);
+
+        // 2 of the 3 fragments arrive and get acked
+        for packet in &packets[..2] {
+            assert_eq!(receiver.receive(packet, start), None);
+        }
+        let now = start + ACK_INTERVAL * 2;
+        for packet in receiver.update(now) {
+            sender.receive(&packet, now);
+        }
+
+        // then nothing gets through for 3 seconds, the receiver drops the incomplete group
+        let outage_end = now + Duration::from_secs(3);
+        let mut now = now;
+        let mut reassembled = None;
+        while now < outage_end + Duration::from_secs(5) && reassembled.is_none() {
+            now += Duration::from_millis(50);
+            let outage = now < outage_end;
+            for packet in sender.update(now) {
+                if outage {
+                    continue;
+                }
+                if let Some(bytes) = receiver.receive(&packet, now) {
+                    reassembled = Some(bytes);
+                }
+            }
+            for packet in receiver.update(now) {
+                if !outage {
+                    sender.receive(&packet, now);
+                }
+            }
+        }
+
+        assert_eq!(reassembled, Some(message));
+        assert_eq!(sender.pending_reliable_count(), 0);
+    }
+}
@@ -0,0 +1,87 @@
+use bincode::Decode;
+use crate::fragment::*;
+
+/// no message can be bigger than what the reassembler puts together from its fragments
+pub const MAX_MESSAGE_SIZE: usize = MAX_PAYLOAD_SIZE * MAX_FRAGMENTS;
+
+/// decodes exactly one value that fills the whole slice
+/// None if the bytes are malformed, if there are bytes left over or if decoding would read or allocate more than LIMIT bytes,
+/// so a made up length in front of a vec cant make the receiver allocate gigabytes
+pub fn decode_exact<T: Decode<()>, const LIMIT: usize>(slice: &[u8]) -> Option<T> {
+    if slice.len() > LIMIT {
+        return None;
+    }
+    let config = bincode::config::standard().with_limit::<LIMIT>();
+    let (value, read) = bincode::decode_from_slice(slice, config).ok()?;
+    // trailing garbage
+    if read != slice.len() {
+        return None;
+    }
+    Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    // varint prefix for a u64 that follows in little endian
+    const U64_PREFIX: u8 = 0xFD;
+
+    fn packet(payload: Option<Datagram>) -> Packet {
+        Packet {
+            header: PacketHeader { sequence: 1, ack: 0, ack_bits: 0 },
+            payload,
+        }
+    }
+
+    // swaps the length prefix at the end of the encoded message for a huge one
+    fn with_huge_length(mut bytes: Vec<u8>) -> Vec<u8> {
+        assert_eq!(bytes.pop(), Some(0));
+        bytes.push(U64_PREFIX);
+        bytes.extend_from_slice(&u64::MAX.to_le_bytes());
+        bytes
+    }
+
+    fn with_trailing_garbage(mut bytes: Vec<u8>) -> Vec<u8> {
+        bytes.extend_from_slice(&[0xFF, 0xFF]);
+        bytes
+    }
+
+    fn malicious_payloads() -> Vec<(&'static str, Vec<u8>)> {
+        let mut truncated_varint = ClientMessage::ping(u64::MAX).encode();
+        truncated_varint.truncate(truncated_varint.len() - 4);
+
+        vec![
+            ("empty", vec![]),
+            ("huge entity package count", with_huge_length(ServerMessage::spawn_entities(1, 0, vec![]).encode())),
+            ("huge component count", with_huge_length(ServerMessage::spawn_entities(1, 0, vec![EntityPackage { net_id: 0, components: vec![] }]).encode())),
+            ("huge input count", with_huge_length(ClientMessage::input(vec![]).encode())),
+            ("huge datagram length", with_huge_length(packet(Some(Datagram::Whole(vec![]))).encode())),
+            ("truncated varint", truncated_varint),
+            ("lone varint prefix", vec![U64_PREFIX]),
+            ("invalid enum tag", vec![0, 0, 200]),
+            ("trailing garbage after server message", with_trailing_garbage(ServerMessage::pong(1, 2).encode())),
+            ("trailing garbage after client message", with_trailing_garbage(ClientMessage::ping(1).encode())),
+            ("trailing garbage after packet", with_trailing_garbage(packet(None).encode())),
+            ("oversize slice", vec![0; MAX_MESSAGE_SIZE + 1]),
+            ("fragment count of zero", packet(Some(Datagram::Fragment { group: 0, index: 0, count: 0, bytes: vec![1, 2, 3] })).encode()),
+            ("fragment index past count", packet(Some(Datagram::Fragment { group: 0, index: 2, count: 2, bytes: vec![1, 2, 3] })).encode()),
+        ]
+    }
+
+    #[test]
+    fn valid_messages_decode() {
+        assert!(ServerMessage::decode(&ServerMessage::pong(1, 2).encode()).is_some());
+        assert!(ClientMessage::decode(&ClientMessage::ping(1).encode()).is_some());
+        assert!(Packet::decode(&packet(Some(Datagram::Fragment { group: 0, index: 1, count: 2, bytes: vec![1, 2, 3] })).encode()).is_some());
+    }
+
+    #[test]
+    fn malicious_payloads_decode_to_none() {
+        for (name, bytes) in malicious_payloads() {
+            assert!(ServerMessage::decode(&bytes).is_none(), "server message decoded from {}", name);
+            assert!(ClientMessage::decode(&bytes).is_none(), "client message decoded from {}", name);
+            assert!(Packet::decode(&bytes).is_none(), "packet decoded from {}", name);
+        }
+    }
+}
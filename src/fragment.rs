@@ -0,0 +1,140 @@
+use bincode::{Decode, Encode};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// biggest datagram that is put on the wire, stays below the usual 1280 ipv6 minimum mtu
+pub const MAX_DATAGRAM_SIZE: usize = 1200;
+/// room left for the datagram framing (enum tag, fragment header, length prefix)
+pub const DATAGRAM_HEADER_RESERVE: usize = 32;
+/// biggest message payload that still fits into a single datagram
+pub const MAX_PAYLOAD_SIZE: usize = MAX_DATAGRAM_SIZE - DATAGRAM_HEADER_RESERVE;
+/// a message can be split into at most this many fragments
+pub const MAX_FRAGMENTS: usize = u8::MAX as usize;
+/// incomplete fragment groups are dropped after this time
+pub const FRAGMENT_TIMEOUT: Duration = Duration::from_secs(2);
+/// how many incomplete groups a single peer can have at the same time
+const MAX_PENDING_GROUPS: usize = 64;
+
+#[derive(Encode, Decode, Debug, Clone)]
+pub enum Datagram {
+    Whole(Vec<u8>),
+    Fragment {
+        group: u16,
+        index: u8,
+        count: u8,
+        bytes: Vec<u8>,
+    },
+}
+
+impl Datagram {
+    /// false for fragments that can not belong to any message the fragmenter makes
+    pub fn is_valid(&self) -> bool {
+        match self {
+            Datagram::Whole(_) => true,
+            Datagram::Fragment { index, count, bytes, .. } => *count > 0 && index < count && bytes.len() <= MAX_PAYLOAD_SIZE,
+        }
+    }
+}
+
+/// splits encoded messages into datagrams that fit the mtu
+#[derive(Default)]
+pub struct Fragmenter {
+    next_group: u16,
+}
+
+impl Fragmenter {
+    /// returns the datagrams for one message, None if the message is too big even for fragmentation
+    pub fn split(&mut self, bytes: Vec<u8>) -> Option<Vec<Datagram>> {
+        if bytes.len() <= MAX_PAYLOAD_SIZE {
+            return Some(vec![Datagram::Whole(bytes)]);
+        }
+
+        let count = bytes.len().div_ceil(MAX_PAYLOAD_SIZE);
+        if count > MAX_FRAGMENTS {
+            return None;
+        }
+
+        let group = self.next_group;
+        self.next_group = self.next_group.wrapping_add(1);
+
+        Some(bytes
+            .chunks(MAX_PAYLOAD_SIZE)
+            .enumerate()
+            .map(|(index, chunk)| Datagram::Fragment {
+                group,
+                index: index as u8,
+                count: count as u8,
+                bytes: chunk.to_vec(),
+            })
+            .collect()
+        )
+    }
+}
+
+struct PartialMessage {
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+    started: Instant,
+}
+
+/// collects fragments of one peer until a message is complete
+#[derive(Default)]
+pub struct Reassembler {
+    groups: HashMap<u16, PartialMessage>,
+}
+
+impl Reassembler {
+    /// false if receive would throw the datagram away, those must not be acked
+    pub fn accepts(&mut self, datagram: &Datagram, now: Instant) -> bool {
+        self.groups.retain(|_, partial| now.duration_since(partial.started) < FRAGMENT_TIMEOUT);
+
+        match datagram {
+            Datagram::Whole(_) => true,
+            datagram if !datagram.is_valid() => false,
+            Datagram::Fragment { group, .. } => self.groups.contains_key(group) || self.groups.len() < MAX_PENDING_GROUPS,
+        }
+    }
+
+    /// returns the payload of a message once all of its fragments arrived
+    pub fn receive(&mut self, datagram: Datagram, now: Instant) -> Option<Vec<u8>> {
+        self.groups.retain(|_, partial| now.duration_since(partial.started) < FRAGMENT_TIMEOUT);
+
+        match datagram {
+            Datagram::Whole(bytes) => Some(bytes),
+            datagram if !datagram.is_valid() => None,
+            Datagram::Fragment { group, index, count, bytes } => {
+                if !self.groups.contains_key(&group) && self.groups.len() >= MAX_PENDING_GROUPS {
+                    return None;
+                }
+
+                let partial = self.groups.entry(group).or_insert_with(|| PartialMessage {
+                    fragments: vec![None; count as usize],
+                    received: 0,
+                    started: now,
+                });
+
+                // the group id got reused with a different layout, start over
+                if partial.fragments.len() != count as usize {
+                    *partial = PartialMessage {
+                        fragments: vec![None; count as usize],
+                        received: 0,
+                        started: now,
+                    };
+                }
+
+                let slot = &mut partial.fragments[index as usize];
+                if slot.is_none() {
+                    *slot = Some(bytes);
+                    partial.received += 1;
+                }
+
+                if partial.received < partial.fragments.len() {
+                    return None;
+                }
+
+                let partial = self.groups.remove(&group)?;
+                Some(partial.fragments.into_iter().flatten().flatten().collect())
+            },
+        }
+    }
+}
@@ -0,0 +1,30 @@
+use bevy::prelude::*;
+use crate::*;
+
+/// shots are checked against where the targets were up to this far in the past, older view ticks are clamped
+pub const MAX_REWIND_MS: u64 = 200;
+/// ticks of hitboxes kept per entity, covers MAX_REWIND_MS at the highest tick rate
+pub const HITBOX_HISTORY: usize = 64;
+
+/// where an entity was at the end of the latest ticks, so shots can be checked against what the shooter saw
+#[derive(Component, Default)]
+pub struct HitboxHistory(VecDeque<(TickType, Vec3, Quat)>);
+
+impl HitboxHistory {
+    pub fn push(&mut self, tick: TickType, position: Vec3, rotation: Quat) {
+        self.0.push_back((tick, position, rotation));
+        if self.0.len() > HITBOX_HISTORY {
+            self.0.pop_front();
+        }
+    }
+
+    /// the position and rotation at the given tick, the closest older one if that tick is missing
+    /// None if the entity didnt exist yet back then
+    pub fn at(&self, tick: TickType) -> Option<(Vec3, Quat)> {
+        self.0
+            .iter()
+            .rev()
+            .find(|(t, _, _)| !sequence_newer(*t, tick))
+            .map(|(_, position, rotation)| (*position, *rotation))
+    }
+}
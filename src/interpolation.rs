@@ -0,0 +1,143 @@
+use bevy::prelude::*;
+use crate::*;
+
+/// environment variable that sets how far behind the server remote entities are shown, in milliseconds
+pub const INTERPOLATION_DELAY_ENV: &str = "INTERPOLATION_DELAY";
+pub const DEFAULT_INTERPOLATION_DELAY_MS: u64 = 100;
+/// how long an entity keeps moving on its last velocity when no new state arrives
+pub const MAX_EXTRAPOLATION_MS: u64 = 250;
+
+const INTERPOLATION_SAMPLES: usize = 32;
+
+/// how remote entities are rendered from the received snapshots
+#[derive(Resource, Debug, Clone, Copy)]
+pub struct InterpolationConfig {
+    pub delay_ms: u64,
+    pub max_extrapolation_ms: u64,
+}
+
+impl Default for InterpolationConfig {
+    fn default() -> Self {
+        Self {
+            delay_ms: DEFAULT_INTERPOLATION_DELAY_MS,
+            max_extrapolation_ms: MAX_EXTRAPOLATION_MS,
+        }
+    }
+}
+
+impl InterpolationConfig {
+    /// reads the delay from INTERPOLATION_DELAY, the default if it is not set or not a number
+    pub fn from_env() -> Self {
+        env_or_default(INTERPOLATION_DELAY_ENV, Self::default(), |value| {
+            value.parse().ok().map(|delay_ms| Self { delay_ms, ..Self::default() })
+        })
+    }
+}
+
+/// the server tick the newest snapshots belong to, runs on with the frame time between them
+/// and is pulled towards every received tick, so jitter in the arrival doesnt make it jump
+#[derive(Resource, Default)]
+pub struct InterpolationClock {
+    // fractional, wrapping of the tick is not handled, that takes over a year at 128 hz
+    server_tick: Option<f64>,
+}
+
+impl InterpolationClock {
+    /// further off than this and the clock jumps instead of catching up
+    const RESYNC_TICKS: f64 = 30.;
+    /// which part of the error is corrected with every snapshot
+    const CATCH_UP: f64 = 0.05;
+
+    pub fn received(&mut self, tick: TickType) {
+        let tick = tick as f64;
+        self.server_tick = match self.server_tick {
+            Some(current) if (tick - current).abs() <= Self::RESYNC_TICKS => Some(current + (tick - current) * Self::CATCH_UP),
+            _ => Some(tick),
+        };
+    }
+
+    pub fn advance(&mut self, delta_secs: f32, tick_rate: &TickRate) {
+        if let Some(current) = self.server_tick.as_mut() {
+            *current += delta_secs as f64 * tick_rate.0 as f64;
+        }
+    }
+
+    /// the tick remote entities are shown at right now
+    pub fn render_tick(&self, config: &InterpolationConfig, tick_rate: &TickRate) -> Option<f64> {
+        Some(self.server_tick? - config.delay_ms as f64 * tick_rate.0 as f64 / 1000.)
+    }
+}
+
+/// one received state of a remote entity
+#[derive(Debug, Clone, Copy)]
+pub struct InterpolationSample {
+    pub tick: TickType,
+    pub position: Vec3,
+    pub rotation: Quat,
+    pub velocity: Vec3,
+    pub look: Option<Quat>,
+}
+
+impl InterpolationSample {
+    pub fn new(tick: TickType, state: &EntityState) -> Self {
+        Self {
+            tick,
+            position: state.position.into(),
+            rotation: state.rotation.into(),
+            velocity: state.velocity.into(),
+            look: state.look.map(Into::into),
+        }
+    }
+}
+
+/// the latest states of a remote entity, it is shown somewhere between them instead of jumping to each one
+#[derive(Component, Default)]
+pub struct InterpolationBuffer(VecDeque<InterpolationSample>);
+
+impl InterpolationBuffer {
+    pub fn push(&mut self, sample: InterpolationSample) {
+        // snapshots that arrive out of order are dropped before they get here, this is just to be sure
+        if self.0.back().is_some_and(|back| back.tick >= sample.tick) {
+            return;
+        }
+        self.0.push_back(sample);
+        if self.0.len() > INTERPOLATION_SAMPLES {
+            self.0.pop_front();
+        }
+    }
+
+    /// the state at the given tick, between the two samples around it
+    /// past the newest sample it moves on with the newest velocity, for at most max_extrapolation_ms
+    pub fn sample(&mut self, render_tick: f64, config: &InterpolationConfig, tick_rate: &TickRate) -> Option<InterpolationSample> {
+        // one sample before the render tick is enough
+        while self.0.get(1).is_some_and(|next| next.tick as f64 <= render_tick) {
+            self.0.pop_front();
+        }
+
+        let from = *self.0.front()?;
+        if render_tick <= from.tick as f64 {
+            return Some(from);
+        }
+
+        let Some(to) = self.0.get(1) else {
+            let max_ticks = config.max_extrapolation_ms as f64 * tick_rate.0 as f64 / 1000.;
+            let ticks = (render_tick - from.tick as f64).min(max_ticks);
+            return Some(InterpolationSample {
+                position: from.position + from.velocity * (ticks as f32 * tick_rate.delta_secs()),
+                ..from
+            });
+        };
+
+        let t = ((render_tick - from.tick as f64) / (to.tick - from.tick) as f64) as f32;
+        Some(InterpolationSample {
+            tick: from.tick,
+            position: from.position.lerp(to.position, t),
+            rotation: from.rotation.slerp(to.rotation, t),
+            velocity: from.velocity.lerp(to.velocity, t),
+            look: match (from.look, to.look) {
+                (Some(from_look), Some(to_look)) => Some(from_look.slerp(to_look, t)),
+                (from_look, to_look) => to_look.or(from_look),
+            },
+        })
+    }
+}
@@ -10,7 +10,35 @@ pub use bevy::{
 use std::net::{SocketAddr, UdpSocket};
 pub use std::collections::VecDeque;
 
-pub type NetIDType = u128;
+mod fragment;
+pub use fragment::*;
+mod connection;
+pub use connection::*;
+mod conditioner;
+pub use conditioner::*;
+mod stats;
+pub use stats::*;
+mod quantize;
+pub use quantize::*;
+mod snapshot;
+pub use snapshot::*;
+mod tick;
+pub use tick::*;
+mod movement;
+pub use movement::*;
+mod interpolation;
+pub use interpolation::*;
+mod hitbox;
+pub use hitbox::*;
+mod decode;
+pub use decode::*;
+mod rate_limit;
+pub use rate_limit::*;
+
+pub type NetIDType = u32;
+
+/// bumped whenever the wire format changes, clients with a different version are rejected
+pub const PROTOCOL_VERSION: u32 = 13;
 
 #[derive(Resource)]
 pub struct CursorPos(pub Vec2);
@@ -82,7 +110,7 @@ pub fn spawn_walls(
     }
 }
 
-#[derive(Encode, Decode, Debug, Clone, Copy)]
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq)]
 pub struct MyVec3 {
     pub x: f32,
     pub y: f32,
@@ -105,7 +133,7 @@ impl Into<MyVec3> for Vec3 {
     }
 }
 
-#[derive(Encode, Decode, Debug, Clone, Copy, Default)]
+#[derive(Encode, Decode, Debug, Clone, Copy, Default, PartialEq)]
 pub struct MyQuat {
     pub x: f32,
     pub y: f32,
@@ -152,28 +180,27 @@ impl Into<MyVec2> for Vec2 {
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
-pub struct PositionPackage {
+pub struct HealthPackage {
     pub net_id: NetIDType,
-    pub position: MyVec3,
-    pub rotation: MyQuat,
+    pub health: f32,
 }
 
-#[derive(Encode, Decode, Debug, Clone)]
-pub struct PlayerLookPackage {
-    pub net_id: NetIDType, // must be player
-    pub rotation: MyQuat,
-}
+/// how many of the newest unacked inputs go into every input message, so a few lost packets dont lose an input
+pub const INPUT_REDUNDANCY: usize = 8;
 
-#[derive(Encode, Decode, Debug, Clone)]
-pub struct VelocityPackage {
-    pub net_id: NetIDType,
-    pub velocity: MyVec3,
-}
-
-#[derive(Encode, Decode, Debug, Clone)]
-pub struct HealthPackage {
-    pub net_id: NetIDType,
-    pub health: f32,
+/// what the player did during one tick, the server runs the movement from it
+#[derive(Encode, Decode, Debug, Clone, Copy)]
+pub struct InputCommand {
+    pub sequence: u32,
+    // x is right, y is forward, both between -1 and 1
+    pub axes: MyVec2,
+    pub jump: bool,
+    // direction of a shot fired in this tick
+    pub fire: Option<MyVec3>,
+    // the server tick other entities were shown at, shots are checked against that
+    pub view_tick: TickType,
+    // rotation of the camera relative to the player
+    pub look: MyQuat,
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
@@ -182,162 +209,282 @@ pub struct EntityPackage {
     pub components: Vec<NetComponent>,
 }
 
+/// room left for the fields of a message around its packages (reliable id, time, enum tag, length prefix)
+pub const MESSAGE_HEADER_RESERVE: usize = 32;
+
+/// splits packages into groups that each fit into a single datagram, measured by their encoded size
+pub fn chunk_packages<T: Encode + Clone>(packages: &[T]) -> Vec<Vec<T>> {
+    let budget = MAX_PAYLOAD_SIZE - MESSAGE_HEADER_RESERVE;
+    let mut chunks = Vec::new();
+    let mut chunk = Vec::new();
+    let mut size = 0;
+    for package in packages {
+        let package_size = bincode::encode_to_vec(package, bincode::config::standard()).unwrap().len();
+        if size + package_size > budget && !chunk.is_empty() {
+            chunks.push(std::mem::take(&mut chunk));
+            size = 0;
+        }
+        chunk.push(package.clone());
+        size += package_size;
+    }
+    if !chunk.is_empty() {
+        chunks.push(chunk);
+    }
+    chunks
+}
+
 #[derive(Encode, Decode, Debug, Clone)]
 pub struct ServerMessage {
-    // 0 means not reliable, otherwise put id so that it can be confirmed, in bevy just put 1 and the network thread will automatically assign
+    // 0 means not reliable, otherwise put id so that it can be acked, in bevy just put 1 and the network thread will automatically assign a per connection id
     pub reliable: usize,
+    // ordered reliable messages are held back by the receiver until all earlier ordered ones arrived
+    pub ordered: bool,
     pub message: ServerMessageInner,
 }
 
 impl ServerMessage {
-    pub fn ok(reliable: usize, net_id: NetIDType) -> Self {
+    pub fn ok(reliable: usize, net_id: NetIDType, tick_rate: u32) -> Self {
         Self {
             reliable,
-            message: ServerMessageInner::Ok(net_id),
+            ordered: true,
+            message: ServerMessageInner::Ok { net_id, tick_rate },
         }
     }
-    pub fn confirm(id: usize) -> Self {
+    // unreliable like everything of the login, the client sends its message again until it gets an answer
+    pub fn challenge(token: u64) -> Self {
         Self {
-            reliable: 1,
-            message: ServerMessageInner::Confirm(id),
+            reliable: 0,
+            ordered: false,
+            message: ServerMessageInner::Challenge(token),
         }
     }
-    pub fn update_healths(packages: Vec<HealthPackage>) -> Self {
+    pub fn rejected(reason: RejectReason) -> Self {
+        Self {
+            reliable: 0,
+            ordered: false,
+            message: ServerMessageInner::Rejected(reason),
+        }
+    }
+    // unreliable, the server drops the connection right after it so nothing would be resent
+    pub fn kicked(reason: String) -> Self {
+        Self {
+            reliable: 0,
+            ordered: false,
+            message: ServerMessageInner::Kicked(reason),
+        }
+    }
+    pub fn pong(client_time: u64, server_time: u64) -> Self {
+        Self {
+            reliable: 0,
+            ordered: false,
+            message: ServerMessageInner::Pong { client_time, server_time },
+        }
+    }
+    pub fn despawn_entities(tick: TickType, net_ids: Vec<NetIDType>) -> Self {
         Self {
             reliable: 1,
-            message: ServerMessageInner::UpdateHealths(packages),
+            ordered: true,
+            message: ServerMessageInner::DespawnEntities { tick, net_ids },
         }
     }
-    pub fn spawn_entities(reliable: usize, packages: Vec<EntityPackage>) -> Self {
+    pub fn update_healths(tick: TickType, packages: Vec<HealthPackage>) -> Self {
         Self {
-            reliable,
-            message: ServerMessageInner::SpawnEntities(packages),
+            reliable: 1,
+            ordered: true,
+            message: ServerMessageInner::UpdateHealths { tick, packages },
         }
     }
-    pub fn update_entities(reliable: usize, packages: Vec<EntityPackage>) -> Self {
+    pub fn spawn_entities(reliable: usize, tick: TickType, packages: Vec<EntityPackage>) -> Self {
         Self {
             reliable,
-            message: ServerMessageInner::UpdateEntities(packages),
+            ordered: true,
+            message: ServerMessageInner::SpawnEntities { tick, packages },
         }
     }
-    pub fn update_positions(unix_time: u64, packages: Vec<PositionPackage>) -> Self {
+    pub fn snapshot(sequence: u32, baseline: Option<u32>, tick: TickType, input_ack: Option<u32>, part: u8, parts: u8, entities: Vec<EntityDelta>) -> Self {
         Self {
             reliable: 0,
-            message: ServerMessageInner::UpdatePositions{unix_time, packages},
+            ordered: false,
+            message: ServerMessageInner::Snapshot{sequence, baseline, tick, input_ack, part, parts, entities},
         }
     }
-    pub fn update_velocities(packages: Vec<VelocityPackage>) -> Self {
+    pub fn shot_result(tick: TickType, sequence: u32, hit: Option<NetIDType>, damage: f32, kill: bool) -> Self {
         Self {
-            reliable: 0,
-            message: ServerMessageInner::UpdateVelocities(packages),
+            reliable: 1,
+            ordered: false,
+            message: ServerMessageInner::ShotResult { tick, sequence, hit, damage, kill },
         }
     }
-    pub fn update_player_looks(packages: Vec<PlayerLookPackage>) -> Self {
+    pub fn shot(tick: TickType, shooter: NetIDType, origin: Vec3, end: Vec3, hit: Option<NetIDType>) -> Self {
         Self {
             reliable: 0,
-            message: ServerMessageInner::UpdatePlayerLooks(packages),
+            ordered: false,
+            message: ServerMessageInner::Shot { tick, shooter, origin: origin.into(), end: end.into(), hit },
         }
     }
 }
 
+#[derive(Encode, Decode, Debug, Clone)]
+pub enum RejectReason {
+    VersionMismatch {
+        server: u32,
+        client: u32,
+    },
+    BadChallenge,
+    ServerFull {
+        max_players: u32,
+    },
+}
+
 #[derive(Encode, Decode, Debug, Clone)]
 pub enum ServerMessageInner {
-    Ok(NetIDType), // the id of the player so that it knows which id it is
-    SpawnEntities(Vec<EntityPackage>),
-    UpdateEntities(Vec<EntityPackage>),
-    UpdatePositions {
-        unix_time: u64,
-        packages: Vec<PositionPackage>,
+    // the id of the player so that it knows which id it is, and the servers tick rate so the client can run at the same rate
+    Ok {
+        net_id: NetIDType,
+        tick_rate: u32,
+    },
+    // the client has to echo this back before it gets a player
+    Challenge(u64),
+    Rejected(RejectReason),
+    // the player was removed by the server, the client should stop
+    Kicked(String),
+    // answer to a ping, client_time is echoed back, server_time is the servers unix time in ms when answering
+    Pong {
+        client_time: u64,
+        server_time: u64,
+    },
+    // state messages carry the server tick they were built in
+    SpawnEntities {
+        tick: TickType,
+        packages: Vec<EntityPackage>,
+    },
+    DespawnEntities {
+        tick: TickType,
+        net_ids: Vec<NetIDType>,
+    },
+    // everything the client sees, compressed against the baseline snapshot it acked, a full snapshot if there is no baseline
+    // split into parts that each fit into a single datagram, a lost part doesnt take the others with it
+    Snapshot {
+        sequence: u32,
+        baseline: Option<u32>,
+        tick: TickType,
+        // the sequence of the last input of this client that went into the snapshot
+        input_ack: Option<u32>,
+        part: u8,
+        parts: u8,
+        entities: Vec<EntityDelta>,
+    },
+    UpdateHealths {
+        tick: TickType,
+        packages: Vec<HealthPackage>,
+    },
+    // to the shooter, what its shot did, sequence is the input it was fired in
+    ShotResult {
+        tick: TickType,
+        sequence: u32,
+        hit: Option<NetIDType>,
+        damage: f32,
+        kill: bool,
+    },
+    // to players near a shot, so they can draw it, end is where it hit or ran out
+    Shot {
+        tick: TickType,
+        shooter: NetIDType,
+        origin: MyVec3,
+        end: MyVec3,
+        hit: Option<NetIDType>,
     },
-    UpdatePlayerLooks(Vec<PlayerLookPackage>),
-    UpdateVelocities(Vec<VelocityPackage>),
-    UpdateHealths(Vec<HealthPackage>),
-    Confirm(usize),
 }
 
 impl ServerMessage {
-    pub fn encode(&self) -> [u8; 1000] {
-        let mut slice = [0u8; 1000];
-        bincode::encode_into_slice(self, &mut slice, bincode::config::standard()).unwrap();
-        slice
+    pub fn encode(&self) -> Vec<u8> {
+        bincode::encode_to_vec(self, bincode::config::standard()).unwrap()
     }
     pub fn decode(slice: &[u8]) -> Option<Self> {
-        let o = bincode::decode_from_slice(slice, bincode::config::standard());
-        match o {
-            Ok(r) => Some(r.0),
-            Err(_) => None,
-        }
+        decode_exact::<Self, MAX_MESSAGE_SIZE>(slice)
     }
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
 pub struct ClientMessage {
     pub reliable: usize,
+    pub ordered: bool,
     pub message: ClientMessageInner,
 }
 
 impl ClientMessage {
+    // unreliable, sent again by the client until the server answers
     pub fn login() -> Self {
         Self {
-            reliable: 1,
-            message: ClientMessageInner::Login,
+            reliable: 0,
+            ordered: false,
+            message: ClientMessageInner::Login { protocol_version: PROTOCOL_VERSION },
         }
     }
-    pub fn setvelocity(me: NetIDType, velocity: MyVec2) -> Self {
+    pub fn challenge_response(token: u64) -> Self {
         Self {
             reliable: 0,
-            message: ClientMessageInner::SetVelocity(me, velocity),
+            ordered: false,
+            message: ClientMessageInner::ChallengeResponse(token),
         }
     }
-    pub fn jump(me: NetIDType) -> Self {
+    // carries the challenge token, so nobody else can disconnect the client
+    pub fn disconnect(token: u64) -> Self {
         Self {
-            reliable: 1,
-            message: ClientMessageInner::Jump(me),
+            reliable: 0,
+            ordered: false,
+            message: ClientMessageInner::Disconnect(token),
         }
     }
-    pub fn shoot(me: NetIDType, direction: MyVec3) -> Self {
+    pub fn ping(client_time: u64) -> Self {
         Self {
-            reliable: 1,
-            message: ClientMessageInner::Shoot(me, direction),
+            reliable: 0,
+            ordered: false,
+            message: ClientMessageInner::Ping(client_time),
         }
     }
-    pub fn rotation(me: NetIDType, rotation: MyQuat) -> Self {
+    // unreliable, a lost ack only means the next snapshot is compressed against an older baseline
+    pub fn snapshot_ack(sequence: u32) -> Self {
         Self {
             reliable: 0,
-            message: ClientMessageInner::Rotation(me, rotation),
+            ordered: false,
+            message: ClientMessageInner::SnapshotAck(sequence),
         }
     }
-    pub fn confirm(id: usize) -> Self {
+    // unreliable, every message repeats the inputs that were not acked yet
+    pub fn input(inputs: Vec<InputCommand>) -> Self {
         Self {
             reliable: 0,
-            message: ClientMessageInner::Confirm(id),
+            ordered: false,
+            message: ClientMessageInner::Input(inputs),
         }
     }
 }
 
 #[derive(Encode, Decode, Debug, Clone)]
 pub enum ClientMessageInner {
-    Login,
-    SetVelocity(NetIDType, MyVec2),
-    Rotation(NetIDType, MyQuat),
-    // confirm an important message from the server, so the server doesnt resend (tcp immitation)
-    Confirm(usize),
-    Jump(NetIDType),
-    Shoot(NetIDType, MyVec3),
+    Login {
+        protocol_version: u32,
+    },
+    ChallengeResponse(u64),
+    // the challenge token of the address, like in the challenge response
+    // also generated by the servers network thread when a client times out
+    Disconnect(u64),
+    // the clients unix time in ms, for measuring the round trip time and the server clock offset
+    Ping(u64),
+    // the sequence of the newest snapshot the client could rebuild
+    SnapshotAck(u32),
+    // the newest inputs of the player, oldest first
+    // there is no net id, the server knows the player from the address it comes from
+    Input(Vec<InputCommand>),
 }
 
 impl ClientMessage {
-    pub fn encode(&self) -> [u8; 1000] {
-        let mut slice = [0u8; 1000];
-        bincode::encode_into_slice(self, &mut slice, bincode::config::standard()).unwrap();
-        slice
+    pub fn encode(&self) -> Vec<u8> {
+        bincode::encode_to_vec(self, bincode::config::standard()).unwrap()
     }
     pub fn decode(slice: &[u8]) -> Option<Self> {
-        let o = bincode::decode_from_slice(slice, bincode::config::standard());
-        match o {
-            Ok(r) => Some(r.0),
-            Err(_) => None,
-        }
+        decode_exact::<Self, MAX_MESSAGE_SIZE>(slice)
     }
 }
 
@@ -384,6 +531,11 @@ impl Into<NetComponent> for Velocity {
         NetComponent::LinearVelocity((self.0).into())
     }
 }
+impl Into<NetComponent> for MovementVelocity {
+    fn into(self) -> NetComponent {
+        NetComponent::LinearVelocity((self.0).into())
+    }
+}
 impl Into<NetComponent> for Transform {
     fn into(self) -> NetComponent {
         NetComponent::Transform {
@@ -443,20 +595,27 @@ impl NetComponent {
                 });
             },
             NetComponent::LinearVelocity(v) => {
+                // remote entities are interpolated from snapshots, not simulated
                 entity.insert(LinearVelocity((*v).into()));
-                entity.insert(RigidBody::Dynamic);
             },
             NetComponent::Sphere(radius) => {
                 entity.insert(Mesh3d(meshes.add(Sphere::new(*radius))));
             },
             NetComponent::SphereCollider(radius) => {
-                entity.insert(Collider::sphere(*radius));
+                // same layers as on the server, so the movement casts against the map dont hit them
+                entity.insert((
+                    Collider::sphere(*radius),
+                    CollisionLayers::new([Layer::Ball], [Layer::Boundary]),
+                ));
             },
             NetComponent::Capsule(radius, height) => {
                 entity.insert(Mesh3d(meshes.add(Capsule3d::new(*radius, *height))));
             },
             NetComponent::CapsuleCollider(radius, height) => {
-                entity.insert(Collider::capsule(*radius, *height));
+                entity.insert((
+                    Collider::capsule(*radius, *height),
+                    CollisionLayers::new([Layer::Player], [Layer::Boundary]),
+                ));
             },
             NetComponent::ColorMaterial { r, g, b } => {
                 entity.insert(MeshMaterial3d(materials.add(Color::srgb(*r, *g, *b))));
@@ -505,58 +664,28 @@ pub fn map_transform() -> Transform {
         .with_scale(Vec3::splat(500.))
 }
 
-#[derive(Debug)]
-pub struct RingBuf<T> {
-    inner: VecDeque<T>,
-    cap: usize,
-}
-
-impl<T> RingBuf<T> {
-    pub fn new(cap: usize) -> Self {
-        Self {
-            inner: VecDeque::with_capacity(cap),
-            cap,
-        }
-    }
-
-    pub fn push(&mut self, item: T) {
-        if self.inner.len() == self.cap {
-            self.inner.pop_back();
-        }
-        self.inner.push_front(item);
-    }
-
-    pub fn iter(&self) -> impl Iterator<Item = &T> {
-        self.inner.iter()
-    }
-    pub fn get(&self, i: usize) -> Option<&T> {
-        self.inner.get(i)
-    }
-    pub fn get_mut(&mut self, i: usize) -> Option<&mut T> {
-        self.inner.get_mut(i)
-    }
-}
-
-#[derive(Resource)]
-pub struct UnixTime(pub u64);
-
-pub struct UnixTimePlugin;
-impl Plugin for UnixTimePlugin {
-    fn build(&self, app: &mut App) {
-        app
-            .insert_resource(UnixTime(0))
-            .add_systems(Update, update_unix_time_system)
-        ;
-    }
-}
-
-fn update_unix_time_system(
-    mut unix_time: ResMut<UnixTime>,
-) {
-    let now = std::time::SystemTime::now()
+/// milliseconds since the unix epoch right now
+pub fn unix_millis() -> u64 {
+    std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
         .unwrap()
-        .as_millis() as u64;
-
-    unix_time.0 = now;
+        .as_millis() as u64
+}
+
+/// reads the environment variable name with parse, the default if it is not set
+/// a value parse doesnt take is reported and the default is used as well
+pub fn env_or_default<T: std::fmt::Debug>(name: &str, default: T, parse: impl FnOnce(&str) -> Option<T>) -> T {
+    let Ok(value) = std::env::var(name) else {
+        return default;
+    };
+    match parse(&value) {
+        Some(parsed) => {
+            println!("{} is set, using {:?}", name, parsed);
+            parsed
+        },
+        None => {
+            println!("invalid {} '{}', using {:?}", name, value, default);
+            default
+        },
+    }
 }
@@ -1,5 +1,5 @@
 
 fn main() {
-    println!("first run the server using 'cargo run -r --bin server' then run the a client using 'cargo run -r --bin client' and optional parameter the address:port which is default '127.0.0.1:7878'")
+    println!("first run the server using 'cargo run -r --bin server' then run the a client using 'cargo run -r --bin client' and optional parameter the address:port which is default '127.0.0.1:7878'. to simulate a bad network set NET_PROFILE to one of off, lan, wifi, slow, mobile or terrible on either side. the server ticks 60 times per second, set TICK_RATE to 30, 60 or 128 to change that. at most 32 players can join, set MAX_PLAYERS on the server to change that. what every address may send is limited, set RATE_LIMIT_PACKETS, RATE_LIMIT_LOGIN, RATE_LIMIT_INPUT, RATE_LIMIT_PING or RATE_LIMIT_CONTROL on the server to per_sec/burst like 150/50 to change that. other players are shown 100 ms behind the server, set INTERPOLATION_DELAY on the client to another number of milliseconds")
 }
 
@@ -0,0 +1,139 @@
+use bevy::prelude::*;
+use crate::*;
+
+/// how players move, the client predicts with the same values the server simulates with
+#[derive(Resource, Debug, Clone, Copy)]
+pub struct MovementConfig {
+    pub speed: f32,
+    pub jump_velocity: f32,
+    // distance kept to walls, so the next cast doesnt start inside them
+    pub collision_skin: f32,
+    // how often one tick can hit something and slide along it
+    pub max_slides: usize,
+    // how far below the player the ground is looked for
+    pub ground_probe: f32,
+    // surfaces with a normal flatter than this count as ground, 0.7 is about 45 degrees
+    pub min_ground_normal: f32,
+}
+
+impl Default for MovementConfig {
+    fn default() -> Self {
+        Self {
+            speed: 8.,
+            jump_velocity: 10.,
+            collision_skin: 0.01,
+            max_slides: 4,
+            ground_probe: 0.05,
+            min_ground_normal: 0.7,
+        }
+    }
+}
+
+/// players stand upright, their capsule is cast with this rotation no matter how the transform is turned
+/// so the client and the server collide the same way
+pub const PLAYER_COLLIDER_ROTATION: Quat = Quat::from_xyzw(std::f32::consts::FRAC_1_SQRT_2, 0., 0., std::f32::consts::FRAC_1_SQRT_2);
+
+/// the velocity of a player, players are kinematic and moved by MovementStep instead of the physics
+#[derive(Component, Debug, Clone, Copy, Default)]
+pub struct MovementVelocity(pub Vec3);
+
+/// where a player is and how fast it moves, before or after one tick
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MovementState {
+    pub position: Vec3,
+    pub velocity: Vec3,
+}
+
+impl MovementConfig {
+    /// the velocity after one tick of input and gravity, without collisions
+    /// jumping only works when standing on something
+    pub fn velocity(&self, mut velocity: Vec3, input: &InputCommand, alive: bool, grounded: bool, gravity: Vec3, delta_secs: f32) -> Vec3 {
+        if alive {
+            // relative to where the camera looks
+            let look: Quat = input.look.into();
+            let (yaw, _pitch, _roll) = look.to_euler(EulerRot::ZXY);
+            let forward_2d = (Quat::from_axis_angle(Vec3::Z, yaw) * Vec3::Y).truncate().normalize_or_zero();
+            let right_2d = Vec2::new(forward_2d.y, -forward_2d.x);
+            let axes: Vec2 = input.axes.into();
+            let dir = (forward_2d * axes.y + right_2d * axes.x).clamp_length_max(1.);
+
+            velocity.x = dir.x * self.speed;
+            velocity.y = dir.y * self.speed;
+            if input.jump && grounded {
+                velocity.z = self.jump_velocity;
+            }
+        }
+        else {
+            velocity.x = 0.;
+            velocity.y = 0.;
+        }
+        velocity + gravity * delta_secs
+    }
+}
+
+/// runs the movement of one player through one tick, the server for every input and the client for its prediction
+pub struct MovementStep<'a, 'w, 's> {
+    pub config: &'a MovementConfig,
+    pub spatial_query: &'a SpatialQuery<'w, 's>,
+    pub gravity: Vec3,
+    pub delta_secs: f32,
+}
+
+impl MovementStep<'_, '_, '_> {
+    pub fn step(&self, collider: &Collider, state: MovementState, input: &InputCommand, alive: bool) -> MovementState {
+        // worked out from the position, so a replay on the client finds the same ground the server did
+        let grounded = self.grounded(collider, state.position);
+        let velocity = self.config.velocity(state.velocity, input, alive, grounded, self.gravity, self.delta_secs);
+        self.move_and_slide(collider, MovementState { position: state.position, velocity })
+    }
+
+    /// true if the map is right below the player and flat enough to stand on
+    pub fn grounded(&self, collider: &Collider, position: Vec3) -> bool {
+        self.spatial_query
+            .cast_shape(
+                collider,
+                position,
+                PLAYER_COLLIDER_ROTATION,
+                Dir3::NEG_Z,
+                &ShapeCastConfig::from_max_distance(self.config.ground_probe + self.config.collision_skin),
+                &SpatialQueryFilter::from_mask(Layer::Boundary),
+            )
+            .is_some_and(|hit| hit.normal1.z >= self.config.min_ground_normal)
+    }
+
+    /// moves along the velocity until something of the map is hit, then slides along it
+    pub fn move_and_slide(&self, collider: &Collider, state: MovementState) -> MovementState {
+        let MovementState { mut position, mut velocity } = state;
+        let filter = SpatialQueryFilter::from_mask(Layer::Boundary);
+
+        let mut remaining = velocity * self.delta_secs;
+        for _ in 0..self.config.max_slides {
+            let Ok(direction) = Dir3::new(remaining) else {
+                break;
+            };
+            let distance = remaining.length();
+            let Some(hit) = self.spatial_query.cast_shape(
+                collider,
+                position,
+                PLAYER_COLLIDER_ROTATION,
+                direction,
+                &ShapeCastConfig::from_max_distance(distance),
+                &filter,
+            ) else {
+                position += remaining;
+                break;
+            };
+
+            let travel = (hit.distance - self.config.collision_skin).max(0.);
+            position += direction * travel;
+
+            // whatever goes into the surface is lost, the rest slides along it
+            let normal = hit.normal1;
+            remaining = direction * (distance - travel);
+            remaining -= normal * remaining.dot(normal);
+            velocity -= normal * velocity.dot(normal).min(0.);
+        }
+
+        MovementState { position, velocity }
+    }
+}
@@ -0,0 +1,207 @@
+use bevy::prelude::*;
+use bincode::{Decode, Encode};
+use crate::*;
+
+/// positions are mapped onto this range on every axis, everything outside gets clamped
+pub const QUANTIZE_RANGE: f32 = HALF_BOUNDARY;
+/// a quantized position is at most this far off on each axis, half a step of 16 bits over the range
+/// plus a few ulps of the range for the float rounding, about 0.008 units with the default boundary
+pub const POSITION_MAX_ERROR: f32 = QUANTIZE_RANGE * 2. / u16::MAX as f32 / 2. + QUANTIZE_RANGE * f32::EPSILON * 4.;
+/// the three smallest quaternion components are at most this far off, half a step of 10 bits over +-1/sqrt(2)
+/// plus a few ulps for the float rounding, which ends up below a quarter of a degree of rotation
+pub const ROTATION_MAX_ERROR: f32 = std::f32::consts::SQRT_2 / ROTATION_STEPS / 2. + f32::EPSILON * 4.;
+
+const ROTATION_BITS: u32 = 10;
+const ROTATION_STEPS: f32 = ((1 << ROTATION_BITS) - 1) as f32;
+
+/// 16 bit fixed point per axis, 6 bytes on the wire instead of 12
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq)]
+pub struct QuantizedVec3(pub [u8; 6]);
+
+impl QuantizedVec3 {
+    pub fn new(v: Vec3) -> Self {
+        let mut bytes = [0; 6];
+        for (i, value) in v.to_array().into_iter().enumerate() {
+            let normalized = ((value + QUANTIZE_RANGE) / (QUANTIZE_RANGE * 2.)).clamp(0., 1.);
+            let fixed = (normalized * u16::MAX as f32).round() as u16;
+            bytes[i * 2..i * 2 + 2].copy_from_slice(&fixed.to_le_bytes());
+        }
+        Self(bytes)
+    }
+
+    pub fn get(&self) -> Vec3 {
+        let mut v = [0.; 3];
+        for (i, value) in v.iter_mut().enumerate() {
+            let fixed = u16::from_le_bytes([self.0[i * 2], self.0[i * 2 + 1]]);
+            *value = fixed as f32 / u16::MAX as f32 * QUANTIZE_RANGE * 2. - QUANTIZE_RANGE;
+        }
+        Vec3::from_array(v)
+    }
+}
+
+/// "smallest three" encoding, the biggest component is left out and rebuilt from the others
+/// 2 bits for its index and 10 bits for each of the others, 4 bytes on the wire instead of 16
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq)]
+pub struct QuantizedQuat(pub [u8; 4]);
+
+impl QuantizedQuat {
+    pub fn new(q: Quat) -> Self {
+        let mut components = q.normalize().to_array();
+        let (largest, _) = components
+            .iter()
+            .enumerate()
+            .fold((0, 0.), |(best, best_abs), (i, c)| if c.abs() > best_abs { (i, c.abs()) } else { (best, best_abs) });
+        // q and -q are the same rotation, so the left out component can always be positive
+        if components[largest] < 0. {
+            for c in components.iter_mut() {
+                *c = -*c;
+            }
+        }
+
+        let mut packed = largest as u32;
+        for (i, c) in components.into_iter().enumerate() {
+            if i == largest {
+                continue;
+            }
+            let normalized = (c * std::f32::consts::SQRT_2 * 0.5 + 0.5).clamp(0., 1.);
+            packed = (packed << ROTATION_BITS) | (normalized * ROTATION_STEPS).round() as u32;
+        }
+        Self(packed.to_le_bytes())
+    }
+
+    pub fn get(&self) -> Quat {
+        let mut packed = u32::from_le_bytes(self.0);
+        let mut smallest = [0.; 3];
+        for c in smallest.iter_mut().rev() {
+            let fixed = packed & ((1 << ROTATION_BITS) - 1);
+            packed >>= ROTATION_BITS;
+            *c = (fixed as f32 / ROTATION_STEPS - 0.5) * 2. * std::f32::consts::FRAC_1_SQRT_2;
+        }
+        let largest = (packed & 0b11) as usize;
+
+        let rest = smallest.iter().map(|c| c * c).sum::<f32>();
+        let mut components = [0.; 4];
+        let mut others = smallest.into_iter();
+        for (i, c) in components.iter_mut().enumerate() {
+            *c = if i == largest { (1. - rest).max(0.).sqrt() } else { others.next().unwrap() };
+        }
+        Quat::from_array(components).normalize()
+    }
+}
+
+/// a position that is either sent with full precision or quantized
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq)]
+pub enum NetVec3 {
+    Full(MyVec3),
+    Quantized(QuantizedVec3),
+}
+
+impl NetVec3 {
+    pub fn new(v: Vec3, quantized: bool) -> Self {
+        if quantized { Self::Quantized(QuantizedVec3::new(v)) } else { Self::Full(v.into()) }
+    }
+}
+
+impl Into<Vec3> for NetVec3 {
+    fn into(self) -> Vec3 {
+        match self {
+            NetVec3::Full(v) => v.into(),
+            NetVec3::Quantized(v) => v.get(),
+        }
+    }
+}
+
+/// a rotation that is either sent with full precision or quantized
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq)]
+pub enum NetQuat {
+    Full(MyQuat),
+    Quantized(QuantizedQuat),
+}
+
+impl NetQuat {
+    pub fn new(q: Quat, quantized: bool) -> Self {
+        if quantized { Self::Quantized(QuantizedQuat::new(q)) } else { Self::Full(q.into()) }
+    }
+}
+
+impl Into<Quat> for NetQuat {
+    fn into(self) -> Quat {
+        match self {
+            NetQuat::Full(q) => q.into(),
+            NetQuat::Quantized(q) => q.get(),
+        }
+    }
+}
+
+/// which update messages the server quantizes, the client understands both either way
+#[derive(Resource, Debug, Clone, Copy)]
+pub struct Quantization {
+    pub positions: bool,
+    pub looks: bool,
+}
+
+impl Default for Quantization {
+    fn default() -> Self {
+        Self {
+            positions: true,
+            looks: true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::*;
+
+    fn random_unit_quat(rng: &mut impl Rng) -> Quat {
+        loop {
+            let v = Vec4::new(
+                rng.random_range(-1.0..1.0),
+                rng.random_range(-1.0..1.0),
+                rng.random_range(-1.0..1.0),
+                rng.random_range(-1.0..1.0),
+            );
+            // inside the unit ball the directions are uniform, so are the rotations
+            let length = v.length();
+            if length > 0.1 && length <= 1. {
+                return Quat::from_vec4(v / length);
+            }
+        }
+    }
+
+    #[test]
+    fn position_roundtrip() {
+        let mut rng = rand::rng();
+        let steps = 100_000;
+        let sweep = (0..=steps).map(|i| -HALF_BOUNDARY + HALF_BOUNDARY * 2. * i as f32 / steps as f32);
+        let random = (0..100_000).map(|_| rng.random_range(-HALF_BOUNDARY..=HALF_BOUNDARY));
+        for value in sweep.chain(random) {
+            let v = Vec3::new(value, -value, value * 0.5);
+            let error = (QuantizedVec3::new(v).get() - v).abs().max_element();
+            assert!(error <= POSITION_MAX_ERROR, "{} off by {}", value, error);
+        }
+
+        // outside of the range it sticks to the edge
+        let outside = Vec3::new(HALF_BOUNDARY * 2., -HALF_BOUNDARY * 2., 0.);
+        let error = (QuantizedVec3::new(outside).get() - Vec3::new(HALF_BOUNDARY, -HALF_BOUNDARY, 0.)).abs().max_element();
+        assert!(error <= POSITION_MAX_ERROR);
+    }
+
+    #[test]
+    fn rotation_roundtrip() {
+        let mut rng = rand::rng();
+        for _ in 0..100_000 {
+            let q = random_unit_quat(&mut rng);
+            let decoded = QuantizedQuat::new(q).get();
+            // q and -q are the same rotation
+            let decoded = if decoded.dot(q) < 0. { -decoded } else { decoded };
+
+            let (q, decoded) = (q.to_array(), decoded.to_array());
+            let largest = (0..4).max_by(|a, b| q[*a].abs().total_cmp(&q[*b].abs())).unwrap();
+            for i in (0..4).filter(|i| *i != largest) {
+                let error = (decoded[i] - q[i]).abs();
+                assert!(error <= ROTATION_MAX_ERROR, "{:?} component {} off by {}", q, i, error);
+            }
+        }
+    }
+}
@@ -0,0 +1,148 @@
+use std::time::Instant;
+use crate::*;
+
+/// environment variable that sets how many players can be connected to the server at once
+pub const MAX_PLAYERS_ENV: &str = "MAX_PLAYERS";
+pub const DEFAULT_MAX_PLAYERS: usize = 32;
+/// environment variables that set the rate limits of every address, as per_sec/burst like 150/50
+pub const RATE_LIMIT_PACKETS_ENV: &str = "RATE_LIMIT_PACKETS";
+pub const RATE_LIMIT_LOGIN_ENV: &str = "RATE_LIMIT_LOGIN";
+pub const RATE_LIMIT_INPUT_ENV: &str = "RATE_LIMIT_INPUT";
+pub const RATE_LIMIT_PING_ENV: &str = "RATE_LIMIT_PING";
+pub const RATE_LIMIT_CONTROL_ENV: &str = "RATE_LIMIT_CONTROL";
+
+/// how many players the server lets in, everyone after that gets rejected with ServerFull
+#[derive(Resource, Debug, Clone, Copy)]
+pub struct MaxPlayers(pub usize);
+
+impl Default for MaxPlayers {
+    fn default() -> Self {
+        Self(DEFAULT_MAX_PLAYERS)
+    }
+}
+
+impl MaxPlayers {
+    /// reads the cap from MAX_PLAYERS, the default if it is not set or not a number
+    pub fn from_env() -> Self {
+        env_or_default(MAX_PLAYERS_ENV, Self::default(), |value| value.parse().ok().map(Self))
+    }
+}
+
+/// client messages are limited per kind, a flood of logins shouldnt eat the budget of the inputs
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageClass {
+    Login,
+    Input,
+    Ping,
+    Control,
+}
+
+impl MessageClass {
+    pub const ALL: [MessageClass; 4] = [MessageClass::Login, MessageClass::Input, MessageClass::Ping, MessageClass::Control];
+}
+
+impl ClientMessageInner {
+    pub fn class(&self) -> MessageClass {
+        match self {
+            ClientMessageInner::Login { .. } | ClientMessageInner::ChallengeResponse(_) => MessageClass::Login,
+            ClientMessageInner::Input(_) => MessageClass::Input,
+            ClientMessageInner::Ping(_) => MessageClass::Ping,
+            ClientMessageInner::SnapshotAck(_) | ClientMessageInner::Disconnect(_) => MessageClass::Control,
+        }
+    }
+}
+
+/// a steady rate with some room for bursts
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimit {
+    pub per_sec: f32,
+    pub burst: f32,
+}
+
+impl RateLimit {
+    /// per_sec/burst, None if it isnt two positive numbers
+    pub fn parse(value: &str) -> Option<Self> {
+        let (per_sec, burst) = value.split_once('/')?;
+        let limit = Self { per_sec: per_sec.trim().parse().ok()?, burst: burst.trim().parse().ok()? };
+        // a burst below 1 would never let anything through
+        (limit.per_sec.is_finite() && limit.per_sec > 0. && limit.burst.is_finite() && limit.burst >= 1.).then_some(limit)
+    }
+}
+
+/// what one address may send of every message class
+#[derive(Debug, Clone, Copy)]
+pub struct RateLimits {
+    // every datagram, checked before it is even decoded
+    pub packets: RateLimit,
+    pub login: RateLimit,
+    // one input message per tick at the highest tick rate
+    pub input: RateLimit,
+    pub ping: RateLimit,
+    // a snapshot ack per snapshot
+    pub control: RateLimit,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self {
+            packets: RateLimit { per_sec: 500., burst: 200. },
+            login: RateLimit { per_sec: 2., burst: 5. },
+            input: RateLimit { per_sec: 150., burst: 50. },
+            ping: RateLimit { per_sec: 5., burst: 10. },
+            control: RateLimit { per_sec: 200., burst: 100. },
+        }
+    }
+}
+
+impl RateLimits {
+    /// every limit from its RATE_LIMIT_ variable, the default for those that are not set or not valid
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        Self {
+            packets: env_or_default(RATE_LIMIT_PACKETS_ENV, default.packets, RateLimit::parse),
+            login: env_or_default(RATE_LIMIT_LOGIN_ENV, default.login, RateLimit::parse),
+            input: env_or_default(RATE_LIMIT_INPUT_ENV, default.input, RateLimit::parse),
+            ping: env_or_default(RATE_LIMIT_PING_ENV, default.ping, RateLimit::parse),
+            control: env_or_default(RATE_LIMIT_CONTROL_ENV, default.control, RateLimit::parse),
+        }
+    }
+
+    pub fn get(&self, class: MessageClass) -> RateLimit {
+        match class {
+            MessageClass::Login => self.login,
+            MessageClass::Input => self.input,
+            MessageClass::Ping => self.ping,
+            MessageClass::Control => self.control,
+        }
+    }
+}
+
+/// fills up at the rate of its limit until burst, every message takes one token
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    limit: RateLimit,
+    tokens: f32,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(limit: RateLimit, now: Instant) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst,
+            last: now,
+        }
+    }
+
+    /// false if the bucket is empty and the message should be dropped
+    pub fn take(&mut self, now: Instant) -> bool {
+        let secs = now.duration_since(self.last).as_secs_f32();
+        self.last = now;
+        self.tokens = (self.tokens + secs * self.limit.per_sec).min(self.limit.burst);
+        if self.tokens < 1. {
+            return false;
+        }
+        self.tokens -= 1.;
+        true
+    }
+}
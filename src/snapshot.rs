@@ -0,0 +1,122 @@
+use bincode::{Decode, Encode};
+use std::collections::{HashMap, VecDeque};
+use crate::*;
+
+/// how many sent / received snapshots are kept around as possible baselines
+pub const SNAPSHOT_HISTORY: usize = 64;
+
+/// the state of one entity as it was put on the wire, so both sides compare the exact same values
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq)]
+pub struct EntityState {
+    pub position: NetVec3,
+    pub rotation: NetQuat,
+    pub velocity: MyVec3,
+    // only players have a look
+    pub look: Option<NetQuat>,
+}
+
+/// what changed about one entity compared to the baseline, None means unchanged
+/// an entity that is not in the baseline has every field set
+#[derive(Encode, Decode, Debug, Clone)]
+pub struct EntityDelta {
+    pub net_id: NetIDType,
+    pub position: Option<NetVec3>,
+    pub rotation: Option<NetQuat>,
+    pub velocity: Option<MyVec3>,
+    pub look: Option<NetQuat>,
+}
+
+/// every entity one client gets to see at one point in time
+#[derive(Debug, Clone, Default)]
+pub struct Snapshot {
+    pub entities: HashMap<NetIDType, EntityState>,
+}
+
+impl Snapshot {
+    /// entities that are left out of the deltas are not part of this snapshot anymore
+    pub fn delta(&self, baseline: Option<&Snapshot>) -> Vec<EntityDelta> {
+        self.entities
+            .iter()
+            .map(|(net_id, state)| {
+                let old = baseline.and_then(|baseline| baseline.entities.get(net_id));
+                EntityDelta {
+                    net_id: *net_id,
+                    position: Some(state.position).filter(|_| old.is_none_or(|old| old.position != state.position)),
+                    rotation: Some(state.rotation).filter(|_| old.is_none_or(|old| old.rotation != state.rotation)),
+                    velocity: Some(state.velocity).filter(|_| old.is_none_or(|old| old.velocity != state.velocity)),
+                    look: state.look.filter(|_| old.is_none_or(|old| old.look != state.look)),
+                }
+            })
+            .collect()
+    }
+
+    /// rebuilds a snapshot from the baseline it was compressed against, None if fields are missing
+    pub fn apply(baseline: Option<&Snapshot>, deltas: Vec<EntityDelta>) -> Option<Snapshot> {
+        let mut snapshot = Snapshot::default();
+        for delta in deltas {
+            let old = baseline.and_then(|baseline| baseline.entities.get(&delta.net_id));
+            let state = EntityState {
+                position: delta.position.or(old.map(|old| old.position))?,
+                rotation: delta.rotation.or(old.map(|old| old.rotation))?,
+                velocity: delta.velocity.or(old.map(|old| old.velocity))?,
+                look: delta.look.or(old.and_then(|old| old.look)),
+            };
+            snapshot.entities.insert(delta.net_id, state);
+        }
+        Some(snapshot)
+    }
+}
+
+/// the latest snapshots of one connection by sequence, on the server also which one got acked
+#[derive(Default)]
+pub struct SnapshotHistory {
+    snapshots: VecDeque<(u32, Snapshot)>,
+    next_sequence: u32,
+    pub acked: Option<u32>,
+}
+
+impl SnapshotHistory {
+    /// stores a snapshot that is about to be sent and returns its sequence
+    pub fn push(&mut self, snapshot: Snapshot) -> u32 {
+        let sequence = self.next_sequence;
+        self.next_sequence = self.next_sequence.wrapping_add(1);
+        self.insert(sequence, snapshot);
+        sequence
+    }
+
+    /// stores a received snapshot under the sequence it came with
+    pub fn insert(&mut self, sequence: u32, snapshot: Snapshot) {
+        self.snapshots.push_back((sequence, snapshot));
+        if self.snapshots.len() > SNAPSHOT_HISTORY {
+            self.snapshots.pop_front();
+        }
+    }
+
+    pub fn get(&self, sequence: u32) -> Option<&Snapshot> {
+        self.snapshots.iter().find(|(s, _)| *s == sequence).map(|(_, snapshot)| snapshot)
+    }
+
+    pub fn latest(&self) -> Option<&(u32, Snapshot)> {
+        self.snapshots.back()
+    }
+
+    /// the newest acked snapshot that is still known, deltas are built against it
+    pub fn baseline(&self) -> Option<(u32, &Snapshot)> {
+        let sequence = self.acked?;
+        Some((sequence, self.get(sequence)?))
+    }
+
+    pub fn acknowledge(&mut self, sequence: u32) {
+        if self.get(sequence).is_none() {
+            return;
+        }
+        if self.acked.is_none_or(|acked| sequence_newer(sequence, acked)) {
+            self.acked = Some(sequence);
+        }
+    }
+}
+
+/// true if s1 is newer than s2, snapshot sequences wrap around like packet sequences
+pub fn sequence_newer(s1: u32, s2: u32) -> bool {
+    s1 != s2 && s1.wrapping_sub(s2) < u32::MAX / 2
+}
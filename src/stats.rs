@@ -0,0 +1,89 @@
+use bevy::prelude::*;
+use std::time::{Duration, Instant};
+use crate::connection::*;
+
+/// how often the network threads send new stats to the game
+pub const NET_STATS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// network numbers of the last interval, summed up over all connections on the server
+#[derive(Resource, Debug, Clone, Default)]
+pub struct NetStats {
+    pub bytes_up_per_sec: f32,
+    pub bytes_down_per_sec: f32,
+    pub packets_up_per_sec: f32,
+    pub packets_down_per_sec: f32,
+    pub resends_per_sec: f32,
+    pub pending_reliable: usize,
+    // averaged over all connections
+    pub rtt_ms: f32,
+    pub loss: f32,
+    pub connections: usize,
+}
+
+/// counts traffic inside of a network thread and turns it into NetStats once per interval
+pub struct NetStatsCollector {
+    since: Instant,
+    bytes_up: usize,
+    bytes_down: usize,
+    packets_up: usize,
+    packets_down: usize,
+}
+
+impl NetStatsCollector {
+    pub fn new(now: Instant) -> Self {
+        Self {
+            since: now,
+            bytes_up: 0,
+            bytes_down: 0,
+            packets_up: 0,
+            packets_down: 0,
+        }
+    }
+
+    pub fn sent(&mut self, bytes: usize) {
+        self.bytes_up += bytes;
+        self.packets_up += 1;
+    }
+
+    pub fn received(&mut self, bytes: usize) {
+        self.bytes_down += bytes;
+        self.packets_down += 1;
+    }
+
+    /// returns the stats once the interval is over and starts counting again
+    pub fn collect<'a, T: 'a>(
+        &mut self,
+        now: Instant,
+        connections: impl Iterator<Item = &'a mut Connection<T>>,
+    ) -> Option<NetStats> {
+        let secs = now.duration_since(self.since).as_secs_f32();
+        if secs < NET_STATS_INTERVAL.as_secs_f32() {
+            return None;
+        }
+
+        let mut stats = NetStats {
+            bytes_up_per_sec: self.bytes_up as f32 / secs,
+            bytes_down_per_sec: self.bytes_down as f32 / secs,
+            packets_up_per_sec: self.packets_up as f32 / secs,
+            packets_down_per_sec: self.packets_down as f32 / secs,
+            ..default()
+        };
+
+        let mut resends = 0;
+        for connection in connections {
+            resends += connection.take_resends();
+            stats.pending_reliable += connection.pending_reliable_count();
+            stats.rtt_ms += connection.rtt_ms;
+            stats.loss += connection.loss;
+            stats.connections += 1;
+        }
+        stats.resends_per_sec = resends as f32 / secs;
+        if stats.connections > 0 {
+            stats.rtt_ms /= stats.connections as f32;
+            stats.loss /= stats.connections as f32;
+        }
+
+        *self = Self::new(now);
+        Some(stats)
+    }
+}
@@ -0,0 +1,62 @@
+use bevy::prelude::*;
+use crate::*;
+
+pub type TickType = u32;
+
+/// environment variable that selects the servers tick rate at startup
+pub const TICK_RATE_ENV: &str = "TICK_RATE";
+/// the tick rates the server can run at, in hz
+pub const TICK_RATES: [u32; 3] = [30, 60, 128];
+pub const DEFAULT_TICK_RATE: u32 = 60;
+
+/// how many fixed updates run per second, physics and networking step with it
+#[derive(Resource, Debug, Clone, Copy, PartialEq)]
+pub struct TickRate(pub u32);
+
+impl Default for TickRate {
+    fn default() -> Self {
+        Self(DEFAULT_TICK_RATE)
+    }
+}
+
+impl TickRate {
+    /// reads the rate from TICK_RATE, the default if it is not set or not one of TICK_RATES
+    pub fn from_env() -> Self {
+        env_or_default(TICK_RATE_ENV, Self::default(), |value| {
+            value.parse().ok().filter(|rate| TICK_RATES.contains(rate)).map(Self)
+        })
+    }
+
+    pub fn delta_secs(&self) -> f32 {
+        1. / self.0 as f32
+    }
+
+    /// how many ticks pass in the given milliseconds, rounded up
+    pub fn ticks_in_millis(&self, millis: u64) -> TickType {
+        (millis * self.0 as u64).div_ceil(1000) as TickType
+    }
+}
+
+/// the number of the fixed update that is running right now, counted up by TickPlugin
+#[derive(Resource, Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Tick(pub TickType);
+
+/// runs FixedUpdate at the tick rate and counts the ticks
+pub struct TickPlugin(pub TickRate);
+
+impl Plugin for TickPlugin {
+    fn build(&self, app: &mut App) {
+        app
+            .insert_resource(self.0)
+            .insert_resource(Time::<Fixed>::from_hz(self.0.0 as f64))
+            .insert_resource(Tick(0))
+            .add_systems(FixedFirst, advance_tick)
+        ;
+    }
+}
+
+fn advance_tick(
+    mut tick: ResMut<Tick>,
+) {
+    tick.0 = tick.0.wrapping_add(1);
+}